@@ -0,0 +1,88 @@
+// Every thread adds to its own counter, so no two threads ever touch the same
+// value. With the counters packed next to each other in an array they still
+// share cache lines, and every add has to take the line away from whichever
+// core had it last (false sharing). Padded, each counter has a line to itself
+// and the threads don't slow each other down at all. Only while the threads
+// really run at the same time though, so past the number of cores the
+// difference shrinks again.
+//
+// ShardedCounter (padded, one shard per CPU) and a single shared AtomicUsize
+// are there for comparison
+//
+// cargo bench --bench counter
+use atomics::counter::{CachePadded, ShardedCounter};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+const OPS: usize = 4_000_000;
+
+// Runs add(thread index) OPS / threads times on each thread
+fn run(threads: usize, add: impl Fn(usize) + Sync) -> Duration {
+    let start = Instant::now();
+    std::thread::scope(|s| {
+        for t in 0..threads {
+            let add = &add;
+            s.spawn(move || {
+                for _ in 0..OPS / threads {
+                    add(t);
+                }
+            });
+        }
+    });
+    start.elapsed()
+}
+
+fn main() {
+    for threads in [1, 2, 4, 8, 16] {
+        let unpadded: Vec<AtomicUsize> = (0..threads).map(|_| AtomicUsize::new(0)).collect();
+        let padded: Vec<CachePadded<AtomicUsize>> = (0..threads)
+            .map(|_| CachePadded(AtomicUsize::new(0)))
+            .collect();
+        let sharded = ShardedCounter::new();
+        let shared = AtomicUsize::new(0);
+
+        let results = [
+            (
+                "unpadded",
+                run(threads, |t| {
+                    unpadded[t].fetch_add(1, Ordering::Relaxed);
+                }),
+            ),
+            (
+                "padded",
+                run(threads, |t| {
+                    padded[t].fetch_add(1, Ordering::Relaxed);
+                }),
+            ),
+            ("ShardedCounter", run(threads, |_| sharded.inc())),
+            (
+                "shared AtomicUsize",
+                run(threads, |_| {
+                    shared.fetch_add(1, Ordering::Relaxed);
+                }),
+            ),
+        ];
+        let total = OPS / threads * threads;
+        assert_eq!(
+            unpadded
+                .iter()
+                .map(|c| c.load(Ordering::Relaxed))
+                .sum::<usize>(),
+            total
+        );
+        assert_eq!(
+            padded
+                .iter()
+                .map(|c| c.load(Ordering::Relaxed))
+                .sum::<usize>(),
+            total
+        );
+        assert_eq!(sharded.get(), total);
+        assert_eq!(shared.load(Ordering::Relaxed), total);
+
+        for (name, elapsed) in results {
+            println!("{:<20} {:>3} threads {:>10.2?}", name, threads, elapsed);
+        }
+        println!();
+    }
+}
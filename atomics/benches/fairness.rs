@@ -0,0 +1,167 @@
+// How fairly each lock in the crate shares itself out. Every thread takes the
+// lock over and over for a fixed time, and we count how often each thread got
+// it and how long each one had to wait.
+//
+// An unfair lock shows up as a big spread in the per-thread counts (one thread
+// keeps re-taking the lock it just released) and a long tail in the wait
+// times. A fair one (TicketLock, McsLock) gives everyone about the same count,
+// but can get less done in total: the lock has to go to the next thread in
+// line even when it isn't running, and that's all of them once there are more
+// threads than cores.
+//
+// cargo bench --bench fairness
+use atomics::{three, McsLock, RwLock, SpinMutex, TicketLock};
+use std::hint::black_box;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+const RUN: Duration = Duration::from_millis(300);
+
+fn critical(v: &mut usize) {
+    *v += 1;
+    for j in 0..50 {
+        black_box(j);
+    }
+}
+
+trait Lock: Sync {
+    fn new() -> Self;
+    fn increment(&self);
+}
+
+impl Lock for three::Mutex<usize> {
+    fn new() -> Self {
+        three::Mutex::new(0)
+    }
+    fn increment(&self) {
+        self.with_lock(critical);
+    }
+}
+
+impl Lock for SpinMutex<usize> {
+    fn new() -> Self {
+        SpinMutex::new(0)
+    }
+    fn increment(&self) {
+        critical(&mut self.lock());
+    }
+}
+
+#[cfg(all(target_os = "linux", not(feature = "loom")))]
+impl Lock for atomics::futex::Mutex<usize> {
+    fn new() -> Self {
+        atomics::futex::Mutex::new(0)
+    }
+    fn increment(&self) {
+        critical(&mut self.lock());
+    }
+}
+
+// only writers, so it is a Mutex too
+impl Lock for RwLock<usize> {
+    fn new() -> Self {
+        RwLock::new(0)
+    }
+    fn increment(&self) {
+        critical(&mut self.write());
+    }
+}
+
+impl Lock for TicketLock<usize> {
+    fn new() -> Self {
+        TicketLock::new(0)
+    }
+    fn increment(&self) {
+        critical(&mut self.lock());
+    }
+}
+
+impl Lock for McsLock<usize> {
+    fn new() -> Self {
+        McsLock::new(0)
+    }
+    fn increment(&self) {
+        critical(&mut self.lock());
+    }
+}
+
+impl Lock for std::sync::Mutex<usize> {
+    fn new() -> Self {
+        std::sync::Mutex::new(0)
+    }
+    fn increment(&self) {
+        critical(&mut self.lock().unwrap());
+    }
+}
+
+// the value at quantile q of sorted
+fn percentile(sorted: &[Duration], q: f64) -> Duration {
+    sorted[((sorted.len() - 1) as f64 * q) as usize]
+}
+
+fn run<L: Lock>(name: &str, threads: usize) {
+    let lock = L::new();
+    let stop = AtomicBool::new(false);
+    // time from asking for the lock to having it, for every acquisition
+    let waits: Vec<Vec<Duration>> = std::thread::scope(|s| {
+        let handles: Vec<_> = (0..threads)
+            .map(|_| {
+                s.spawn(|| {
+                    let mut waits = Vec::new();
+                    while !stop.load(Ordering::Relaxed) {
+                        let start = Instant::now();
+                        lock.increment();
+                        // includes the critical section, which is the same
+                        // for every lock
+                        waits.push(start.elapsed());
+                        // a little work outside the lock
+                        for j in 0..20 {
+                            black_box(j);
+                        }
+                    }
+                    waits
+                })
+            })
+            .collect();
+        std::thread::sleep(RUN);
+        stop.store(true, Ordering::Relaxed);
+        handles.into_iter().map(|h| h.join().unwrap()).collect()
+    });
+
+    let counts: Vec<usize> = waits.iter().map(Vec::len).collect();
+    let total: usize = counts.iter().sum();
+    let min = *counts.iter().min().unwrap();
+    let max = *counts.iter().max().unwrap();
+    let mut all: Vec<Duration> = waits.into_iter().flatten().collect();
+    all.sort();
+    println!(
+        "{:<18} {:>2} threads {:>9} total, per thread {:>8} min {:>8} max \
+         | wait p50 {:>9.2?} p99 {:>9.2?} p99.9 {:>9.2?} max {:>9.2?}",
+        name,
+        threads,
+        total,
+        min,
+        max,
+        percentile(&all, 0.5),
+        percentile(&all, 0.99),
+        percentile(&all, 0.999),
+        all[all.len() - 1],
+    );
+    if threads <= 4 {
+        println!("{:<18} {:>2} counts  {:?}", "", threads, counts);
+    }
+}
+
+fn main() {
+    for threads in [2, 4, 8] {
+        run::<three::Mutex<usize>>("three::Mutex", threads);
+        run::<SpinMutex<usize>>("SpinMutex", threads);
+        #[cfg(all(target_os = "linux", not(feature = "loom")))]
+        run::<atomics::futex::Mutex<usize>>("futex::Mutex", threads);
+        run::<RwLock<usize>>("RwLock (write)", threads);
+        run::<TicketLock<usize>>("TicketLock", threads);
+        run::<McsLock<usize>>("McsLock", threads);
+        run::<std::sync::Mutex<usize>>("std::sync::Mutex", threads);
+        println!();
+    }
+}
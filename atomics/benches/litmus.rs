@@ -0,0 +1,32 @@
+// Every litmus shape under every ordering combination, a million runs each,
+// with the histogram of outcomes. Exits with an error if a forbidden outcome
+// showed up, see src/litmus.rs
+//
+// cargo bench --bench litmus [runs]
+#[cfg(not(feature = "loom"))]
+use atomics::litmus;
+
+#[cfg(feature = "loom")]
+fn main() {
+    eprintln!("the litmus tests run on the real CPU, build without the loom feature");
+}
+
+#[cfg(not(feature = "loom"))]
+fn main() {
+    // cargo bench passes --bench, skip anything that isn't a number
+    let runs = std::env::args()
+        .skip(1)
+        .find_map(|a| a.parse().ok())
+        .unwrap_or(1_000_000);
+    let mut failed = 0;
+    for report in litmus::run_all(runs) {
+        println!("{}", report);
+        if report.forbidden_seen() > 0 {
+            failed += 1;
+        }
+    }
+    if failed > 0 {
+        eprintln!("{} litmus tests saw a forbidden outcome", failed);
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,131 @@
+// Threads fighting over one counter, for each of the mutexes. Each thread does
+// a bit of work outside the lock, so there is some chance of finding it free.
+// Prints the time for all the increments and the CPU time used, which is where
+// spinning shows up: the spinlocks keep every waiting core busy, the futex
+// Mutex (and std's, which is also futex based on Linux) let them sleep
+//
+// cargo bench --bench mutex
+use atomics::three;
+use atomics::SpinMutex;
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+const OPS: usize = 200_000;
+
+// long enough that threads regularly find the lock taken
+fn critical(v: &mut usize) {
+    *v += 1;
+    for j in 0..50 {
+        black_box(j);
+    }
+}
+
+trait Lock: Sync {
+    fn new() -> Self;
+    fn increment(&self);
+    fn get(&self) -> usize;
+}
+
+impl Lock for three::Mutex<usize> {
+    fn new() -> Self {
+        three::Mutex::new(0)
+    }
+    fn increment(&self) {
+        self.with_lock(critical);
+    }
+    fn get(&self) -> usize {
+        self.with_lock(|v| *v)
+    }
+}
+
+impl Lock for SpinMutex<usize> {
+    fn new() -> Self {
+        SpinMutex::new(0)
+    }
+    fn increment(&self) {
+        critical(&mut self.lock());
+    }
+    fn get(&self) -> usize {
+        *self.lock()
+    }
+}
+
+#[cfg(all(target_os = "linux", not(feature = "loom")))]
+impl Lock for atomics::futex::Mutex<usize> {
+    fn new() -> Self {
+        atomics::futex::Mutex::new(0)
+    }
+    fn increment(&self) {
+        critical(&mut self.lock());
+    }
+    fn get(&self) -> usize {
+        *self.lock()
+    }
+}
+
+impl Lock for std::sync::Mutex<usize> {
+    fn new() -> Self {
+        std::sync::Mutex::new(0)
+    }
+    fn increment(&self) {
+        critical(&mut self.lock().unwrap());
+    }
+    fn get(&self) -> usize {
+        *self.lock().unwrap()
+    }
+}
+
+// user + system time of the whole process so far
+#[cfg(target_os = "linux")]
+fn cpu_time() -> Duration {
+    let mut usage = std::mem::MaybeUninit::<libc::rusage>::uninit();
+    // SAFETY: getrusage fills in the struct when it returns 0
+    let usage = unsafe {
+        assert_eq!(libc::getrusage(libc::RUSAGE_SELF, usage.as_mut_ptr()), 0);
+        usage.assume_init()
+    };
+    let tv = |t: libc::timeval| Duration::new(t.tv_sec as u64, t.tv_usec as u32 * 1000);
+    tv(usage.ru_utime) + tv(usage.ru_stime)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn cpu_time() -> Duration {
+    Duration::ZERO
+}
+
+fn run<L: Lock>(name: &str, threads: usize) {
+    let lock = L::new();
+    let cpu = cpu_time();
+    let start = Instant::now();
+    std::thread::scope(|s| {
+        for _ in 0..threads {
+            s.spawn(|| {
+                for i in 0..OPS / threads {
+                    lock.increment();
+                    // work outside the lock
+                    for j in 0..20 {
+                        black_box(i ^ j);
+                    }
+                }
+            });
+        }
+    });
+    let elapsed = start.elapsed();
+    let cpu = cpu_time() - cpu;
+    assert_eq!(lock.get(), OPS / threads * threads);
+    println!(
+        "{:<20} {:>3} threads {:>10.2?} wall {:>10.2?} cpu",
+        name, threads, elapsed, cpu
+    );
+}
+
+fn main() {
+    for threads in [1, 2, 4, 8, 16] {
+        run::<three::Mutex<usize>>("three::Mutex", threads);
+        run::<SpinMutex<usize>>("SpinMutex", threads);
+        #[cfg(all(target_os = "linux", not(feature = "loom")))]
+        run::<atomics::futex::Mutex<usize>>("futex::Mutex", threads);
+        run::<std::sync::Mutex<usize>>("std::sync::Mutex", threads);
+        println!();
+    }
+}
@@ -0,0 +1,92 @@
+// Producers sending to one consumer, through MsQueue, panama's Mutex +
+// Condvar channel and std's mpsc. The channels only allow one receiver, so
+// the consumer is always a single thread, the threads count is producers.
+//
+// MsQueue has no blocking pop, the consumer just yields when it finds it
+// empty. The channel's receiver sleeps on the Condvar instead, and every send
+// takes the Mutex, which is what the producers end up fighting over
+//
+// cargo bench --bench queue
+use atomics::queue::MsQueue;
+use std::time::{Duration, Instant};
+
+const OPS: usize = 400_000;
+
+fn ms_queue(threads: usize) -> Duration {
+    let q = MsQueue::new();
+    let start = Instant::now();
+    std::thread::scope(|s| {
+        for _ in 0..threads {
+            s.spawn(|| {
+                for i in 0..OPS / threads {
+                    q.push(i);
+                }
+            });
+        }
+        let mut received = 0;
+        while received < OPS / threads * threads {
+            match q.try_pop() {
+                Some(_) => received += 1,
+                None => std::thread::yield_now(),
+            }
+        }
+    });
+    start.elapsed()
+}
+
+fn panama(threads: usize) -> Duration {
+    let (tx, mut rx) = panama::one::channel();
+    let start = Instant::now();
+    std::thread::scope(|s| {
+        for _ in 0..threads {
+            let mut tx = tx.clone();
+            s.spawn(move || {
+                for i in 0..OPS / threads {
+                    tx.send(i);
+                }
+            });
+        }
+        // one::Receiver can't tell when the senders are gone, count instead
+        for _ in 0..OPS / threads * threads {
+            rx.recv();
+        }
+    });
+    start.elapsed()
+}
+
+fn mpsc(threads: usize) -> Duration {
+    let (tx, rx) = std::sync::mpsc::channel();
+    let start = Instant::now();
+    std::thread::scope(|s| {
+        for _ in 0..threads {
+            let tx = tx.clone();
+            s.spawn(move || {
+                for i in 0..OPS / threads {
+                    tx.send(i).unwrap();
+                }
+            });
+        }
+        for _ in 0..OPS / threads * threads {
+            rx.recv().unwrap();
+        }
+    });
+    start.elapsed()
+}
+
+fn main() {
+    for threads in [1, 4, 16] {
+        for (name, run) in [
+            ("MsQueue", ms_queue as fn(usize) -> Duration),
+            ("panama::one", panama),
+            ("std::sync::mpsc", mpsc),
+        ] {
+            println!(
+                "{:<20} {:>3} threads {:>10.2?}",
+                name,
+                threads,
+                run(threads)
+            );
+        }
+        println!();
+    }
+}
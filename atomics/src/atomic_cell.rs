@@ -0,0 +1,484 @@
+// An atomic for any Copy type, not just the integers std has atomics for.
+//
+// If T happens to be the size of one of the native atomics (1, 2, 4, 8 or 16
+// bytes), aligned at least as strictly, and has no uninitialised bytes (see
+// below), its bytes are loaded and stored through that atomic and nothing ever waits. Otherwise every access takes a
+// spinlock from a global table, picked by the cell's address. The table is
+// shared by every AtomicCell in the program, so the locks are spread over a
+// bunch of cache lines (striped) to keep unrelated cells from contending on
+// the same one. is_lock_free says which of the two a T gets.
+//
+// 16 bytes needs cmpxchg16b, which isn't in the baseline x86_64 that Rust
+// builds for, and std has no stable AtomicU128. With
+//   RUSTFLAGS="-C target-feature=+cmpxchg16b"   (or -C target-cpu=native)
+// it is used, otherwise 16 byte types go to the locks as well.
+//
+// The native path copies T's bytes into an integer, and reading uninitialised
+// bytes (padding, MaybeUninit, unions) as an integer is UB. crossbeam's
+// AtomicCell does it anyway for any T of the right size, so a safe
+// AtomicCell::new(MaybeUninit::<u32>::uninit()).load() is UB there, and so is
+// a #[repr(align(4))] struct S(u8), which is 3 bytes of padding. Here T has to
+// implement AtomicValue, and only says it has no uninitialised bytes by
+// setting NO_UNINIT, which takes an unsafe impl. Everything else goes to the
+// locks. Without specialization there is no way to ask whether T implements a
+// trait from generic code, which is why it is a const and not a marker trait
+// like bytemuck's NoUninit.
+//
+// Not built under loom: it casts the cell's memory to std atomics, which loom
+// has no way to follow
+use crate::counter::CachePadded;
+use crate::SpinMutex;
+use std::cell::UnsafeCell;
+use std::fmt;
+use std::mem::{self, size_of, MaybeUninit};
+use std::sync::atomic::{AtomicU16, AtomicU32, AtomicU64, AtomicU8, Ordering};
+
+#[repr(transparent)]
+pub struct AtomicCell<T> {
+    v: UnsafeCell<T>,
+}
+
+// Like a Mutex, T values move between threads but are never shared, they are
+// always copied in and out
+unsafe impl<T: Send> Sync for AtomicCell<T> {}
+
+/// Anything an AtomicCell can hold. For a type of your own,
+/// `unsafe impl AtomicValue for Rgb {}` always takes the locks, and is
+/// trivially fine.
+///
+/// # Safety
+///
+/// Setting `NO_UNINIT` to true promises that every byte of every value is
+/// initialised: no padding, `MaybeUninit` or unions anywhere inside it. The
+/// native path reads the value as an integer and relies on that.
+pub unsafe trait AtomicValue: Copy {
+    const NO_UNINIT: bool = false;
+}
+
+macro_rules! no_uninit {
+    ($($t:ty)*) => {$(
+        // SAFETY: primitives, every byte is part of the value
+        unsafe impl AtomicValue for $t {
+            const NO_UNINIT: bool = true;
+        }
+    )*};
+}
+no_uninit!(u8 u16 u32 u64 u128 usize i8 i16 i32 i64 i128 isize f32 f64 bool char);
+
+// SAFETY: the address, and the length or vtable, all initialised
+unsafe impl<T: ?Sized> AtomicValue for *const T {
+    const NO_UNINIT: bool = true;
+}
+
+// SAFETY: as above
+unsafe impl<T: ?Sized> AtomicValue for *mut T {
+    const NO_UNINIT: bool = true;
+}
+
+// SAFETY: arrays have no padding between elements, they are as initialised as
+// the elements are
+unsafe impl<T: AtomicValue, const N: usize> AtomicValue for [T; N] {
+    const NO_UNINIT: bool = T::NO_UNINIT;
+}
+
+// the default, it may be uninitialised all the way through
+unsafe impl<T: Copy> AtomicValue for MaybeUninit<T> {}
+
+// Whether T can be accessed as an A: no uninitialised bytes, same size, and at
+// least as aligned
+const fn fits<T: AtomicValue, A>() -> bool {
+    T::NO_UNINIT
+        && size_of::<T>() == size_of::<A>()
+        && mem::align_of::<T>() >= mem::align_of::<A>()
+}
+
+// Runs $native with $a bound to the cell's memory as the matching atomic, and
+// the type $int as its integer type, or $fallback if there is none
+macro_rules! atomic {
+    ($T:ty, $ptr:expr, |$a:ident: $int:ident| $native:expr, $fallback:expr) => {{
+        if fits::<$T, AtomicU8>() {
+            type $int = u8;
+            // SAFETY: same size, and aligned enough
+            let $a = unsafe { &*($ptr as *const AtomicU8) };
+            $native
+        } else if fits::<$T, AtomicU16>() {
+            type $int = u16;
+            // SAFETY: as above
+            let $a = unsafe { &*($ptr as *const AtomicU16) };
+            $native
+        } else if fits::<$T, AtomicU32>() {
+            type $int = u32;
+            // SAFETY: as above
+            let $a = unsafe { &*($ptr as *const AtomicU32) };
+            $native
+        } else if fits::<$T, AtomicU64>() {
+            type $int = u64;
+            // SAFETY: as above
+            let $a = unsafe { &*($ptr as *const AtomicU64) };
+            $native
+        } else {
+            #[cfg(all(target_arch = "x86_64", target_feature = "cmpxchg16b"))]
+            if fits::<$T, AtomicU128>() {
+                type $int = u128;
+                // SAFETY: as above
+                let $a = unsafe { &*($ptr as *const AtomicU128) };
+                #[allow(clippy::needless_return)]
+                return $native;
+            }
+            $fallback
+        }
+    }};
+}
+
+impl<T: AtomicValue> AtomicCell<T> {
+    pub const fn new(t: T) -> Self {
+        AtomicCell {
+            v: UnsafeCell::new(t),
+        }
+    }
+
+    // Whether T goes through a native atomic, or takes a lock
+    pub const fn is_lock_free() -> bool {
+        let native = fits::<T, AtomicU8>()
+            || fits::<T, AtomicU16>()
+            || fits::<T, AtomicU32>()
+            || fits::<T, AtomicU64>();
+        #[cfg(all(target_arch = "x86_64", target_feature = "cmpxchg16b"))]
+        let native = native || fits::<T, AtomicU128>();
+        native
+    }
+
+    // Acquire, so we see everything the thread that stored it did before
+    pub fn load(&self) -> T {
+        atomic!(
+            T,
+            self.v.get(),
+            |a: Int| {
+                // SAFETY: Int is the same size as T, and it was a valid T
+                unsafe { mem::transmute_copy::<Int, T>(&a.load(Ordering::Acquire)) }
+            },
+            {
+                let _guard = lock(self.v.get() as usize);
+                // SAFETY: the lock keeps writers out
+                unsafe { self.v.get().read() }
+            }
+        )
+    }
+
+    // Release, pairs with load
+    pub fn store(&self, t: T) {
+        atomic!(
+            T,
+            self.v.get(),
+            |a: Int| {
+                // SAFETY: Int is the same size as T, and T has no
+                // uninitialised bytes (NO_UNINIT)
+                a.store(
+                    unsafe { mem::transmute_copy::<T, Int>(&t) },
+                    Ordering::Release,
+                )
+            },
+            {
+                let _guard = lock(self.v.get() as usize);
+                // SAFETY: the lock keeps everyone else out
+                unsafe { self.v.get().write(t) }
+            }
+        )
+    }
+
+    pub fn swap(&self, t: T) -> T {
+        atomic!(
+            T,
+            self.v.get(),
+            |a: Int| {
+                // SAFETY: as in load and store
+                unsafe {
+                    let old = a.swap(mem::transmute_copy::<T, Int>(&t), Ordering::AcqRel);
+                    mem::transmute_copy::<Int, T>(&old)
+                }
+            },
+            {
+                let _guard = lock(self.v.get() as usize);
+                // SAFETY: the lock keeps everyone else out
+                unsafe { self.v.get().replace(t) }
+            }
+        )
+    }
+
+    pub fn into_inner(self) -> T {
+        self.v.into_inner()
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.v.get_mut()
+    }
+}
+
+impl<T: AtomicValue + Eq> AtomicCell<T> {
+    // Stores new if the value is equal to current. Returns the value before,
+    // as Ok if it was replaced and Err if not, like the std atomics
+    pub fn compare_exchange(&self, current: T, new: T) -> Result<T, T> {
+        atomic!(
+            T,
+            self.v.get(),
+            |a: Int| {
+                // SAFETY: as in store
+                let mut expected = unsafe { mem::transmute_copy::<T, Int>(&current) };
+                let new = unsafe { mem::transmute_copy::<T, Int>(&new) };
+                loop {
+                    match a.compare_exchange(expected, new, Ordering::AcqRel, Ordering::Acquire) {
+                        Ok(old) => break Ok(unsafe { mem::transmute_copy::<Int, T>(&old) }),
+                        Err(old) => {
+                            // SAFETY: it was a valid T
+                            let old_t = unsafe { mem::transmute_copy::<Int, T>(&old) };
+                            // Different bytes, but maybe still equal (an Eq
+                            // that isn't bytewise). Try again with the bytes
+                            // that are actually there
+                            if old_t != current {
+                                break Err(old_t);
+                            }
+                            expected = old;
+                        }
+                    }
+                }
+            },
+            {
+                // T's Eq runs with the lock held, so it must not touch any
+                // AtomicCell that takes the locks, not just this one. The
+                // table is global, another cell can hash to the same lock and
+                // it would spin forever
+                let _guard = lock(self.v.get() as usize);
+                // SAFETY: the lock keeps everyone else out
+                unsafe {
+                    let old = self.v.get().read();
+                    if old == current {
+                        self.v.get().write(new);
+                        Ok(old)
+                    } else {
+                        Err(old)
+                    }
+                }
+            }
+        )
+    }
+}
+
+impl<T: AtomicValue + Default> Default for AtomicCell<T> {
+    fn default() -> Self {
+        AtomicCell::new(T::default())
+    }
+}
+
+impl<T: AtomicValue> From<T> for AtomicCell<T> {
+    fn from(t: T) -> Self {
+        AtomicCell::new(t)
+    }
+}
+
+impl<T: AtomicValue + fmt::Debug> fmt::Debug for AtomicCell<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AtomicCell")
+            .field("value", &self.load())
+            .finish()
+    }
+}
+
+// A prime number of them, so cells spaced out by a power of two (the usual
+// size of things in an array) still land on different locks
+const STRIPES: usize = 67;
+
+static LOCKS: [CachePadded<SpinMutex<()>>; STRIPES] =
+    [const { CachePadded(SpinMutex::new(())) }; STRIPES];
+
+fn lock(addr: usize) -> crate::SpinMutexGuard<'static, ()> {
+    LOCKS[addr % STRIPES].lock()
+}
+
+// std's AtomicU128 isn't stable, this is just enough of one. Every operation,
+// even a load, is a cmpxchg16b, x86 has no plain 16 byte atomic load or store
+#[cfg(all(target_arch = "x86_64", target_feature = "cmpxchg16b"))]
+#[repr(transparent)]
+struct AtomicU128(UnsafeCell<u128>);
+
+#[cfg(all(target_arch = "x86_64", target_feature = "cmpxchg16b"))]
+impl AtomicU128 {
+    fn compare_exchange(
+        &self,
+        current: u128,
+        new: u128,
+        success: Ordering,
+        failure: Ordering,
+    ) -> Result<u128, u128> {
+        // SAFETY: u128 is 16 byte aligned on x86_64, and cmpxchg16b is atomic
+        let old =
+            unsafe { std::arch::x86_64::cmpxchg16b(self.0.get(), current, new, success, failure) };
+        if old == current {
+            Ok(old)
+        } else {
+            Err(old)
+        }
+    }
+
+    fn load(&self, order: Ordering) -> u128 {
+        // swaps 0 for 0, or fails and tells us what is there. Either way
+        // nothing changes
+        match self.compare_exchange(0, 0, order, order) {
+            Ok(v) | Err(v) => v,
+        }
+    }
+
+    fn swap(&self, new: u128, order: Ordering) -> u128 {
+        let mut current = self.load(Ordering::Relaxed);
+        loop {
+            match self.compare_exchange(current, new, order, Ordering::Relaxed) {
+                Ok(v) => return v,
+                Err(v) => current = v,
+            }
+        }
+    }
+
+    fn store(&self, new: u128, order: Ordering) {
+        // success ordering of a compare_exchange can be Release, but the load
+        // half then is Relaxed
+        self.swap(new, order);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{AtomicCell, AtomicValue};
+    use std::mem::MaybeUninit;
+    use std::thread;
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+    struct Rgb {
+        r: u8,
+        g: u8,
+        b: u8,
+    }
+
+    unsafe impl AtomicValue for Rgb {}
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+    #[repr(align(8))]
+    struct Pair {
+        a: u32,
+        b: u32,
+    }
+
+    // SAFETY: two u32s fill all 8 bytes, there is no padding
+    unsafe impl AtomicValue for Pair {
+        const NO_UNINIT: bool = true;
+    }
+
+    // 4 bytes and aligned to 4, but 3 of them are padding
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+    #[repr(align(4))]
+    struct Padded(u8);
+
+    unsafe impl AtomicValue for Padded {}
+
+    #[test]
+    fn lock_free_sizes() {
+        assert!(AtomicCell::<u8>::is_lock_free());
+        assert!(AtomicCell::<i16>::is_lock_free());
+        assert!(AtomicCell::<char>::is_lock_free());
+        assert!(AtomicCell::<usize>::is_lock_free());
+        assert!(AtomicCell::<Pair>::is_lock_free());
+        // 3 bytes
+        assert!(!AtomicCell::<Rgb>::is_lock_free());
+        // the right size, but only aligned to 1
+        assert!(!AtomicCell::<[u8; 4]>::is_lock_free());
+        assert!(!AtomicCell::<[u64; 3]>::is_lock_free());
+        // the right size and alignment, but not all initialised
+        assert!(!AtomicCell::<Padded>::is_lock_free());
+        assert!(!AtomicCell::<MaybeUninit<u32>>::is_lock_free());
+        assert_eq!(
+            AtomicCell::<u128>::is_lock_free(),
+            cfg!(all(target_arch = "x86_64", target_feature = "cmpxchg16b"))
+        );
+    }
+
+    // Goes through every operation, for T on either path
+    fn ops<T: AtomicValue + Eq + std::fmt::Debug>(a: T, b: T, c: T) {
+        let cell = AtomicCell::new(a);
+        assert_eq!(cell.load(), a);
+        cell.store(b);
+        assert_eq!(cell.load(), b);
+        assert_eq!(cell.swap(c), b);
+        assert_eq!(cell.compare_exchange(a, b), Err(c));
+        assert_eq!(cell.load(), c);
+        assert_eq!(cell.compare_exchange(c, a), Ok(c));
+        assert_eq!(cell.into_inner(), a);
+    }
+
+    #[test]
+    fn native() {
+        ops(1u8, 2, 3);
+        ops(1u16, 2, 3);
+        ops('a', 'b', 'c');
+        ops(1u64, u64::MAX, 0);
+        ops(Pair { a: 1, b: 2 }, Pair { a: 3, b: 4 }, Pair::default());
+        ops(1u128, u128::MAX, 0);
+    }
+
+    #[test]
+    fn locked() {
+        ops(
+            Rgb { r: 1, g: 2, b: 3 },
+            Rgb { r: 4, g: 5, b: 6 },
+            Rgb::default(),
+        );
+        ops([1u64; 3], [2; 3], [3; 3]);
+        ops(Padded(1), Padded(2), Padded(3));
+        // safe to load, it is only ever copied as a MaybeUninit
+        let cell = AtomicCell::new(MaybeUninit::<u32>::uninit());
+        cell.store(MaybeUninit::new(7));
+        assert_eq!(unsafe { cell.load().assume_init() }, 7);
+        let cell = AtomicCell::from([1u8; 4]);
+        assert_eq!(format!("{:?}", cell), "AtomicCell { value: [1, 1, 1, 1] }");
+    }
+
+    // Every increment goes through a compare_exchange loop, none get lost
+    fn increments<T: AtomicValue + Eq + Send>(zero: T, inc: fn(T) -> T, get: fn(T) -> u64) {
+        let cell = AtomicCell::new(zero);
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..5000 {
+                        let mut cur = cell.load();
+                        while let Err(now) = cell.compare_exchange(cur, inc(cur)) {
+                            cur = now;
+                        }
+                    }
+                });
+            }
+        });
+        assert_eq!(get(cell.load()), 4 * 5000);
+    }
+
+    #[test]
+    fn contended() {
+        increments(0u64, |v| v + 1, |v| v);
+        increments(
+            Pair::default(),
+            |p| Pair {
+                a: p.a + 1,
+                b: p.b + 1,
+            },
+            |p| {
+                assert_eq!(p.a, p.b);
+                p.a as u64
+            },
+        );
+        // both halves move together, or a torn load would show it
+        increments(
+            [0u64; 3],
+            |v| [v[0] + 1, v[1] + 1, v[2] + 1],
+            |v| {
+                assert!(v[0] == v[1] && v[1] == v[2]);
+                v[0]
+            },
+        );
+    }
+}
@@ -0,0 +1,220 @@
+// Counters, the simplest thing to do with fetch_add.
+//
+// SeqGen hands out unique ids. fetch_add is a single read-modify-write, so no
+// two threads can ever get the same value back, whatever the ordering: every
+// operation on one atomic goes into its modification order one after the
+// other. Relaxed is enough for that. SeqCst only matters if the ids also have
+// to agree with the order of other SeqCst operations, e.g. "if I saw your
+// flag, my id is bigger than yours".
+//
+// ShardedCounter is for counting things (requests, bytes, ..) from lots of
+// threads at once. A single AtomicUsize is correct, but every fetch_add needs
+// the cache line in exclusive state, so with many threads the line spends its
+// time going from core to core. Instead each CPU gets its own shard to add
+// to, and reading adds up all the shards. Increments are cheap, reads are
+// not, which is the right trade for a counter that is bumped all the time and
+// looked at once a second.
+//
+// The shards have to be on different cache lines. Two atomics next to each
+// other in memory share a line, and then the cores still fight over it even
+// though they never touch each other's value (false sharing). CachePadded
+// takes care of that, see benches/counter.rs for what it costs without it
+use crate::sync::{AtomicU64, AtomicUsize, Ordering};
+use std::ops::{Deref, DerefMut};
+
+pub struct SeqGen {
+    next: AtomicU64,
+    ordering: Ordering,
+}
+
+impl SeqGen {
+    // Relaxed, see above
+    pub fn new() -> Self {
+        SeqGen::with_ordering(Ordering::Relaxed)
+    }
+
+    pub fn with_ordering(ordering: Ordering) -> Self {
+        // fetch_add takes any ordering, but only these two make sense for ids
+        assert!(
+            matches!(ordering, Ordering::Relaxed | Ordering::SeqCst),
+            "SeqGen ordering must be Relaxed or SeqCst"
+        );
+        SeqGen {
+            next: AtomicU64::new(0),
+            ordering,
+        }
+    }
+
+    // Even at a billion ids a second, a u64 lasts for 500 years
+    pub fn next(&self) -> u64 {
+        self.next.fetch_add(1, self.ordering)
+    }
+
+    // The id the next call to next will return, unless another thread gets
+    // there first
+    pub fn peek(&self) -> u64 {
+        self.next.load(self.ordering)
+    }
+}
+
+impl Default for SeqGen {
+    fn default() -> Self {
+        SeqGen::new()
+    }
+}
+
+// Aligns T to the start of a cache line, and pads it to fill it. x86_64 and
+// aarch64 use 64 byte lines, but the prefetcher pulls them in in pairs, so
+// neighbours 64 bytes away still interfere
+#[cfg_attr(any(target_arch = "x86_64", target_arch = "aarch64"), repr(align(128)))]
+#[cfg_attr(
+    not(any(target_arch = "x86_64", target_arch = "aarch64")),
+    repr(align(64))
+)]
+#[derive(Debug, Default)]
+pub struct CachePadded<T>(pub T);
+
+impl<T> Deref for CachePadded<T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T> DerefMut for CachePadded<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.0
+    }
+}
+
+pub struct ShardedCounter {
+    shards: Box<[CachePadded<AtomicUsize>]>,
+}
+
+impl ShardedCounter {
+    // One shard per CPU
+    pub fn new() -> Self {
+        let cpus = std::thread::available_parallelism().map_or(1, |n| n.get());
+        ShardedCounter::with_shards(cpus)
+    }
+
+    pub fn with_shards(n: usize) -> Self {
+        assert!(n > 0, "ShardedCounter needs at least one shard");
+        ShardedCounter {
+            shards: (0..n).map(|_| CachePadded(AtomicUsize::new(0))).collect(),
+        }
+    }
+
+    pub fn add(&self, n: usize) {
+        // Relaxed, a counter doesn't order anything else. Which shard we land
+        // on only matters for speed, if the thread moves to another CPU right
+        // after shard() it just shares a shard for a moment
+        self.shards[shard() % self.shards.len()].fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    // Not a snapshot, adds that happen while we go through the shards may or
+    // may not be counted. Once the adding threads are done (joined), it is
+    // exact
+    pub fn get(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|s| s.load(Ordering::Relaxed))
+            .fold(0, usize::wrapping_add)
+    }
+}
+
+impl Default for ShardedCounter {
+    fn default() -> Self {
+        ShardedCounter::new()
+    }
+}
+
+// The CPU we are running on. On Linux that's sched_getcpu, which goes through
+// the vDSO and doesn't actually enter the kernel
+#[cfg(all(target_os = "linux", not(feature = "loom")))]
+fn shard() -> usize {
+    // SAFETY: no arguments, returns -1 on error
+    let cpu = unsafe { libc::sched_getcpu() };
+    if cpu >= 0 {
+        cpu as usize
+    } else {
+        thread_shard()
+    }
+}
+
+#[cfg(not(all(target_os = "linux", not(feature = "loom"))))]
+fn shard() -> usize {
+    thread_shard()
+}
+
+// Otherwise spread the threads over the shards by giving each one a number
+// the first time it adds something
+fn thread_shard() -> usize {
+    // std's atomic even under loom, this only picks a shard
+    static NEXT: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+    thread_local! {
+        static SHARD: usize = NEXT.fetch_add(1, Ordering::Relaxed);
+    }
+    SHARD.with(|s| *s)
+}
+
+#[cfg(all(test, not(feature = "loom")))]
+mod test {
+    use super::{CachePadded, SeqGen, ShardedCounter};
+    use std::sync::atomic::Ordering;
+    use std::thread;
+
+    #[test]
+    fn seq_gen_ids_are_unique() {
+        for ordering in [Ordering::Relaxed, Ordering::SeqCst] {
+            let g = SeqGen::with_ordering(ordering);
+            let mut ids: Vec<u64> = thread::scope(|s| {
+                let handles: Vec<_> = (0..4)
+                    .map(|_| s.spawn(|| (0..10000).map(|_| g.next()).collect::<Vec<_>>()))
+                    .collect();
+                handles
+                    .into_iter()
+                    .flat_map(|h| h.join().unwrap())
+                    .collect()
+            });
+            ids.sort();
+            assert_eq!(ids, (0..40000).collect::<Vec<_>>());
+            assert_eq!(g.peek(), 40000);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "Relaxed or SeqCst")]
+    fn seq_gen_rejects_acquire() {
+        SeqGen::with_ordering(Ordering::Acquire);
+    }
+
+    #[test]
+    fn sharded_counter_counts() {
+        let c = ShardedCounter::with_shards(3);
+        thread::scope(|s| {
+            for _ in 0..8 {
+                s.spawn(|| {
+                    for _ in 0..10000 {
+                        c.inc();
+                    }
+                    c.add(5);
+                });
+            }
+        });
+        assert_eq!(c.get(), 8 * 10005);
+    }
+
+    #[test]
+    fn padded_shards_dont_share_lines() {
+        assert!(std::mem::align_of::<CachePadded<u8>>() >= 64);
+        let c = ShardedCounter::with_shards(2);
+        let a = &*c.shards[0] as *const _ as usize;
+        let b = &*c.shards[1] as *const _ as usize;
+        assert!(b - a >= 64);
+    }
+}
@@ -0,0 +1,299 @@
+// Epoch based memory reclamation, a small version of crossbeam-epoch.
+//
+// The problem: in a lock-free structure, thread A loads a pointer to a node,
+// and before it gets to read the node thread B unlinks it and frees it. A is
+// now reading freed memory. Worse, the allocator may hand the same address out
+// again for a new node, and A's compare_exchange on the old pointer succeeds
+// even though it is a different node now (the ABA problem).
+//
+// The fix is to not free an unlinked node until every thread that might still
+// have a pointer to it is done. Threads pin the collector while they look at
+// shared nodes, and the collector keeps a global epoch counter:
+//
+// - pin() records the current global epoch in a slot and marks it pinned
+// - the global epoch only moves from e to e + 1 when every pinned thread is in
+//   epoch e, so threads are always pinned in the global epoch or the one before
+// - an unlinked node is stamped with the global epoch g at the time, and freed
+//   once the global epoch has reached g + 2. Anyone who could have loaded the
+//   pointer was pinned in g or earlier, and the epoch couldn't have moved past
+//   g + 1 without all of them unpinning
+//
+// Unlike crossbeam there are no thread locals: each pin claims a free slot
+// from the collector (or adds a new one), and each data structure owns its own
+// Collector, which keeps everything usable under loom
+use crate::sync::{fence, maybe_const_fn, AtomicBool, AtomicPtr, AtomicUsize, Ordering};
+use crate::SpinMutex;
+use std::collections::VecDeque;
+use std::ptr;
+
+pub struct Collector {
+    epoch: AtomicUsize,
+    // Linked list of slots, only ever pushed to, and freed when the
+    // collector is dropped
+    slots: AtomicPtr<Slot>,
+    // Unlinked nodes waiting for the epoch to move on, oldest first. Only
+    // touched when something is deferred, so a lock is fine here
+    garbage: SpinMutex<VecDeque<Deferred>>,
+}
+
+struct Slot {
+    // whether a Guard is using this slot
+    claimed: AtomicBool,
+    // epoch << 1 | 1 while pinned, 0 otherwise
+    epoch: AtomicUsize,
+    next: *mut Slot,
+}
+
+struct Deferred {
+    epoch: usize,
+    ptr: *mut (),
+    destroy: unsafe fn(*mut ()),
+}
+
+// The pointer is only freed, by whichever thread happens to collect it.
+// defer_destroy's safety contract says that's fine
+unsafe impl Send for Deferred {}
+
+// Shared pointers loaded while a Guard is alive stay valid until it is dropped
+pub struct Guard<'a> {
+    collector: &'a Collector,
+    slot: &'a Slot,
+}
+
+impl Collector {
+    maybe_const_fn! {
+        pub fn new() -> Self {
+            Collector {
+                epoch: AtomicUsize::new(0),
+                slots: AtomicPtr::new(ptr::null_mut()),
+                garbage: SpinMutex::new(VecDeque::new()),
+            }
+        }
+    }
+
+    pub fn pin(&self) -> Guard<'_> {
+        let slot = self.claim_slot();
+        let e = self.epoch.load(Ordering::Relaxed);
+        slot.epoch.store(e << 1 | 1, Ordering::Relaxed);
+        // SeqCst fence so that either try_advance (which has its own fence
+        // before looking at the slots) sees us pinned, or we see every unlink
+        // that happened before its fence. Without it the store above could
+        // become visible after our loads of the data structure
+        fence(Ordering::SeqCst);
+        Guard {
+            collector: self,
+            slot,
+        }
+    }
+
+    fn claim_slot(&self) -> &Slot {
+        let mut p = self.slots.load(Ordering::Acquire);
+        while !p.is_null() {
+            // SAFETY: slots are only freed when the collector is dropped
+            let slot = unsafe { &*p };
+            if !slot.claimed.load(Ordering::Relaxed)
+                && slot
+                    .claimed
+                    .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+                    .is_ok()
+            {
+                return slot;
+            }
+            p = slot.next;
+        }
+
+        // all taken, push a new one
+        let slot = Box::into_raw(Box::new(Slot {
+            claimed: AtomicBool::new(true),
+            epoch: AtomicUsize::new(0),
+            next: ptr::null_mut(),
+        }));
+        let mut head = self.slots.load(Ordering::Relaxed);
+        loop {
+            // SAFETY: nobody else can see the slot until the exchange succeeds
+            unsafe { (*slot).next = head };
+            // Release so whoever walks the list sees next filled in
+            match self
+                .slots
+                .compare_exchange_weak(head, slot, Ordering::Release, Ordering::Relaxed)
+            {
+                // SAFETY: as above, slots live as long as the collector
+                Ok(_) => return unsafe { &*slot },
+                Err(h) => head = h,
+            }
+        }
+    }
+
+    // Moves the global epoch on by one if every pinned thread has caught up
+    // with it. Returns the global epoch afterwards
+    fn try_advance(&self) -> usize {
+        let e = self.epoch.load(Ordering::Relaxed);
+        // pairs with the fence in pin, see there
+        fence(Ordering::SeqCst);
+        let mut p = self.slots.load(Ordering::Acquire);
+        while !p.is_null() {
+            // SAFETY: slots are only freed when the collector is dropped
+            let slot = unsafe { &*p };
+            // Acquire pairs with the Release in Guard's drop, so whatever the
+            // thread read while pinned happens before we free anything
+            let s = slot.epoch.load(Ordering::Acquire);
+            if s & 1 == 1 && s >> 1 != e {
+                return e;
+            }
+            p = slot.next;
+        }
+        match self
+            .epoch
+            .compare_exchange(e, e + 1, Ordering::Release, Ordering::Relaxed)
+        {
+            Ok(_) => e + 1,
+            // someone else moved it on already
+            Err(now) => now,
+        }
+    }
+
+    // Frees everything that has been waiting for two epochs
+    fn collect(&self) {
+        let e = self.try_advance();
+        let ready: Vec<Deferred> = {
+            let mut garbage = self.garbage.lock();
+            // Stamps can be pushed slightly out of order (the epoch is read
+            // before taking the lock), stopping at the first one that isn't
+            // ready only frees that one a bit later, and saves looking at
+            // all of them every time
+            let n = garbage
+                .iter()
+                .position(|d| d.epoch + 2 > e)
+                .unwrap_or(garbage.len());
+            garbage.drain(..n).collect()
+        };
+        // outside the lock, destroying can take a while
+        for d in ready {
+            // SAFETY: see defer_destroy
+            unsafe { (d.destroy)(d.ptr) };
+        }
+    }
+}
+
+impl Default for Collector {
+    fn default() -> Self {
+        Collector::new()
+    }
+}
+
+impl Drop for Collector {
+    fn drop(&mut self) {
+        // &mut self, so no Guards are left and nothing can still be looking
+        // at the garbage
+        for d in self.garbage.lock().drain(..) {
+            // SAFETY: see defer_destroy
+            unsafe { (d.destroy)(d.ptr) };
+        }
+        let mut p = self.slots.load(Ordering::Relaxed);
+        while !p.is_null() {
+            // SAFETY: every slot came from Box::into_raw, and nobody uses
+            // them anymore
+            let slot = unsafe { Box::from_raw(p) };
+            p = slot.next;
+        }
+    }
+}
+
+unsafe fn destroy_box<T>(p: *mut ()) {
+    drop(Box::from_raw(p as *mut T));
+}
+
+impl Guard<'_> {
+    /// Frees `ptr` (as a `Box<T>`) once no thread can still be using it.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must come from `Box::into_raw`, be unlinked already, so no thread
+    /// that pins from now on can get to it, and only be deferred once. It may
+    /// be freed on any thread, so dropping a `T` must be fine on any thread.
+    pub unsafe fn defer_destroy<T>(&self, ptr: *mut T) {
+        // The stamp has to be read after the unlink. If a thread loaded the
+        // pointer before the unlink, then it pinned before it too (see pin),
+        // so it is pinned in this epoch or an earlier one
+        fence(Ordering::SeqCst);
+        let epoch = self.collector.epoch.load(Ordering::Relaxed);
+        self.collector.garbage.lock().push_back(Deferred {
+            epoch,
+            ptr: ptr as *mut (),
+            destroy: destroy_box::<T>,
+        });
+        self.collector.collect();
+    }
+}
+
+impl Drop for Guard<'_> {
+    fn drop(&mut self) {
+        // Release, so everything we read while pinned happens before the
+        // thread that sees us unpinned frees anything
+        self.slot.epoch.store(0, Ordering::Release);
+        self.slot.claimed.store(false, Ordering::Release);
+    }
+}
+
+#[cfg(all(test, not(feature = "loom")))]
+mod test {
+    use super::Collector;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    struct D<'a>(&'a AtomicUsize);
+
+    impl Drop for D<'_> {
+        fn drop(&mut self) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn waits_for_pinned_threads() {
+        let drops = AtomicUsize::new(0);
+        let c = Collector::new();
+        let reader = c.pin();
+
+        let g = c.pin();
+        unsafe { g.defer_destroy(Box::into_raw(Box::new(D(&drops)))) };
+        drop(g);
+        for _ in 0..10 {
+            c.collect();
+        }
+        // the reader might still be looking at it
+        assert_eq!(drops.load(Ordering::Relaxed), 0);
+
+        drop(reader);
+        c.collect();
+        c.collect();
+        assert_eq!(drops.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn slots_are_reused() {
+        let c = Collector::new();
+        let a = c.pin();
+        let b = c.pin();
+        assert!(!std::ptr::eq(a.slot, b.slot));
+        let b_slot = b.slot as *const _;
+        drop(b);
+        let b2 = c.pin();
+        assert!(std::ptr::eq(b2.slot, b_slot));
+        drop((a, b2));
+    }
+
+    #[test]
+    fn drop_frees_garbage() {
+        let drops = AtomicUsize::new(0);
+        let c = Collector::new();
+        let g = c.pin();
+        for _ in 0..3 {
+            unsafe { g.defer_destroy(Box::into_raw(Box::new(D(&drops)))) };
+        }
+        // we are still pinned in the epoch they were deferred in
+        assert_eq!(drops.load(Ordering::Relaxed), 0);
+        drop(g);
+        drop(c);
+        assert_eq!(drops.load(Ordering::Relaxed), 3);
+    }
+}
@@ -0,0 +1,434 @@
+// A Mutex that sleeps instead of spinning. one, two and three (and SpinMutex)
+// keep a waiting thread busy checking the lock. That's fine for a few cycles,
+// but if the holder is descheduled the waiters burn whole time slices for
+// nothing. The futex syscall lets a thread sleep until someone says the value
+// of an AtomicU32 may have changed, without any kernel object to set up:
+//
+//   futex_wait(&a, expected): sleep, but only if a still == expected
+//                             (checked by the kernel, so no lost wake ups)
+//   futex_wake(&a, n):        wake up to n threads sleeping on a
+//
+// A syscall costs much more than an atomic op, so the fast paths must avoid
+// them. The lock word has three states so unlock knows if anyone is asleep:
+//
+//   UNLOCKED   nobody holds the lock
+//   LOCKED     held, nobody waiting, unlock doesn't need to wake anyone
+//   CONTENDED  held, and there may be threads asleep in futex_wait
+//
+// Linux only, other OSes have their own versions (WaitOnAddress, __ulock_wait)
+use std::cell::UnsafeCell;
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+const UNLOCKED: u32 = 0;
+const LOCKED: u32 = 1;
+const CONTENDED: u32 = 2;
+
+// Returns false if the timeout ran out. It can also return early for no reason
+// (a spurious wake up, or a signal), so always call it in a loop that checks
+// the condition again
+fn futex_wait(a: &AtomicU32, expected: u32, timeout: Option<Duration>) -> bool {
+    let ts = timeout.map(|d| libc::timespec {
+        // saturate rather than wrap around into a negative timeout
+        tv_sec: d.as_secs().try_into().unwrap_or(libc::time_t::MAX),
+        tv_nsec: d.subsec_nanos() as _,
+    });
+    let ts_ptr = ts
+        .as_ref()
+        .map_or(std::ptr::null(), |ts| ts as *const libc::timespec);
+    // SAFETY: a is a valid AtomicU32 for the whole call, and ts_ptr is either
+    // null (wait forever) or points to a timespec that outlives the call
+    // PRIVATE because the futex is never shared with another process, which
+    // lets the kernel skip some work
+    let r = unsafe {
+        libc::syscall(
+            libc::SYS_futex,
+            a.as_ptr(),
+            libc::FUTEX_WAIT | libc::FUTEX_PRIVATE_FLAG,
+            expected,
+            ts_ptr,
+        )
+    };
+    !(r < 0 && std::io::Error::last_os_error().raw_os_error() == Some(libc::ETIMEDOUT))
+}
+
+fn futex_wake(a: &AtomicU32, n: i32) {
+    // SAFETY: a is a valid AtomicU32, wake never touches any other memory
+    unsafe {
+        libc::syscall(
+            libc::SYS_futex,
+            a.as_ptr(),
+            libc::FUTEX_WAKE | libc::FUTEX_PRIVATE_FLAG,
+            n,
+        );
+    }
+}
+
+pub struct Mutex<T> {
+    state: AtomicU32,
+    v: UnsafeCell<T>,
+}
+
+unsafe impl<T> Sync for Mutex<T> where T: Send {}
+
+pub struct MutexGuard<'a, T> {
+    lock: &'a Mutex<T>,
+}
+
+// A shared guard gives out &T to every thread that has it
+unsafe impl<T> Sync for MutexGuard<'_, T> where T: Sync {}
+
+impl<T> Mutex<T> {
+    pub const fn new(t: T) -> Self {
+        Self {
+            state: AtomicU32::new(UNLOCKED),
+            v: UnsafeCell::new(t),
+        }
+    }
+
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        // Fast path, no syscalls. Acquire/Relaxed for the same reason as in
+        // three::Mutex
+        if self
+            .state
+            .compare_exchange(UNLOCKED, LOCKED, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            self.lock_contended();
+        }
+        MutexGuard { lock: self }
+    }
+
+    // Kept out of lock so the fast path gets inlined
+    #[cold]
+    fn lock_contended(&self) {
+        // Critical sections are usually short, so spin for a little while
+        // first, it's often cheaper than going to sleep and being woken.
+        // Only while LOCKED: if it is CONTENDED there are already threads
+        // asleep, and they will be woken before we get a turn anyway
+        let mut spins = 0;
+        while self.state.load(Ordering::Relaxed) == LOCKED && spins < 100 {
+            std::hint::spin_loop();
+            spins += 1;
+        }
+        if self
+            .state
+            .compare_exchange(UNLOCKED, LOCKED, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+        {
+            return;
+        }
+
+        // Take the lock as CONTENDED, not LOCKED. We can't tell whether other
+        // threads are still asleep, so whoever unlocks after us has to check
+        // with the kernel. Waking nobody is only a wasted syscall, not waking
+        // a sleeper would be a deadlock
+        // swap returns what was there before: UNLOCKED means we got it
+        while self.state.swap(CONTENDED, Ordering::Acquire) != UNLOCKED {
+            // sleeps only if the state is still CONTENDED when the kernel
+            // checks, so an unlock in between the swap and here isn't missed
+            futex_wait(&self.state, CONTENDED, None);
+        }
+    }
+
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        self.state
+            .compare_exchange(UNLOCKED, LOCKED, Ordering::Acquire, Ordering::Relaxed)
+            .ok()
+            .map(|_| MutexGuard { lock: self })
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.v.get_mut()
+    }
+
+    pub fn into_inner(self) -> T {
+        self.v.into_inner()
+    }
+}
+
+impl<T: Default> Default for Mutex<T> {
+    fn default() -> Self {
+        Mutex::new(T::default())
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for Mutex<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut d = f.debug_struct("Mutex");
+        match self.try_lock() {
+            Some(guard) => d.field("data", &&*guard),
+            None => d.field("data", &format_args!("<locked>")),
+        };
+        d.finish_non_exhaustive()
+    }
+}
+
+impl<T> Deref for MutexGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        // SAFETY: we hold the lock
+        unsafe { &*self.lock.v.get() }
+    }
+}
+
+impl<T> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        // SAFETY: we hold the lock, and &mut self stops the guard handing out
+        // two of these
+        unsafe { &mut *self.lock.v.get() }
+    }
+}
+
+impl<T> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        // Release pairs with the Acquire in lock. Only go to the kernel if
+        // someone might be asleep
+        if self.lock.state.swap(UNLOCKED, Ordering::Release) == CONTENDED {
+            futex_wake(&self.lock.state, 1);
+        }
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for MutexGuard<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+// Wait until another thread says something changed. The thing that changed is
+// protected by the Mutex, so wait unlocks it while asleep and locks it again
+// before returning.
+//
+// counter is bumped by every notify. A waiter reads it before unlocking the
+// mutex and only sleeps if it hasn't changed since, so a notify that comes in
+// after the unlock but before the futex_wait can't be lost
+pub struct Condvar {
+    counter: AtomicU32,
+    // so notify can skip the syscall when nobody is waiting
+    waiters: AtomicUsize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WaitTimeoutResult(bool);
+
+impl WaitTimeoutResult {
+    pub fn timed_out(&self) -> bool {
+        self.0
+    }
+}
+
+impl Condvar {
+    pub const fn new() -> Self {
+        Self {
+            counter: AtomicU32::new(0),
+            waiters: AtomicUsize::new(0),
+        }
+    }
+
+    // Can wake up without a notify (spurious wake up), so call it in a loop
+    // that checks what you're waiting for
+    pub fn wait<'a, T>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        self.wait_inner(guard, None).0
+    }
+
+    pub fn wait_while<'a, T, F>(
+        &self,
+        mut guard: MutexGuard<'a, T>,
+        mut condition: F,
+    ) -> MutexGuard<'a, T>
+    where
+        F: FnMut(&mut T) -> bool,
+    {
+        while condition(&mut *guard) {
+            guard = self.wait(guard);
+        }
+        guard
+    }
+
+    // Like wait, but gives up after dur. Also spurious, so the timeout not
+    // having run out doesn't mean someone called notify
+    pub fn wait_timeout<'a, T>(
+        &self,
+        guard: MutexGuard<'a, T>,
+        dur: Duration,
+    ) -> (MutexGuard<'a, T>, WaitTimeoutResult) {
+        let deadline = Instant::now().checked_add(dur);
+        let (guard, woken) = self.wait_inner(guard, Some(dur));
+        // futex_wait can come back with EINTR just before the time is up,
+        // go by the clock rather than by what the kernel said
+        let timed_out = !woken || deadline.is_some_and(|d| Instant::now() >= d);
+        (guard, WaitTimeoutResult(timed_out))
+    }
+
+    fn wait_inner<'a, T>(
+        &self,
+        guard: MutexGuard<'a, T>,
+        timeout: Option<Duration>,
+    ) -> (MutexGuard<'a, T>, bool) {
+        // Relaxed is enough for both: the notifying thread changed whatever we
+        // are waiting for under the mutex, and our unlock/lock of the mutex
+        // orders these against that
+        self.waiters.fetch_add(1, Ordering::Relaxed);
+        let counter = self.counter.load(Ordering::Relaxed);
+
+        let mutex = guard.lock;
+        drop(guard);
+        let woken = futex_wait(&self.counter, counter, timeout);
+
+        self.waiters.fetch_sub(1, Ordering::Relaxed);
+        (mutex.lock(), woken)
+    }
+
+    pub fn notify_one(&self) {
+        if self.waiters.load(Ordering::Relaxed) > 0 {
+            self.counter.fetch_add(1, Ordering::Relaxed);
+            futex_wake(&self.counter, 1);
+        }
+    }
+
+    pub fn notify_all(&self) {
+        if self.waiters.load(Ordering::Relaxed) > 0 {
+            self.counter.fetch_add(1, Ordering::Relaxed);
+            futex_wake(&self.counter, i32::MAX);
+        }
+    }
+}
+
+impl Default for Condvar {
+    fn default() -> Self {
+        Condvar::new()
+    }
+}
+
+impl fmt::Debug for Condvar {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Condvar").finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Condvar, Mutex, CONTENDED, UNLOCKED};
+    use std::sync::atomic::Ordering;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn lock_and_try_lock() {
+        let m = Mutex::new(1);
+        let g = m.lock();
+        assert!(m.try_lock().is_none());
+        assert_eq!(format!("{:?}", m), "Mutex { data: <locked>, .. }");
+        drop(g);
+        *m.try_lock().unwrap() += 1;
+        assert_eq!(m.into_inner(), 2);
+    }
+
+    #[test]
+    fn stress() {
+        let m = Mutex::new(0);
+        thread::scope(|s| {
+            for _ in 0..8 {
+                s.spawn(|| {
+                    for _ in 0..10000 {
+                        *m.lock() += 1;
+                    }
+                });
+            }
+        });
+        assert_eq!(*m.lock(), 8 * 10000);
+        // everybody woke up and left
+        assert_eq!(m.state.load(Ordering::Relaxed), UNLOCKED);
+    }
+
+    #[test]
+    fn sleeps_while_held() {
+        let m = Mutex::new(0);
+        thread::scope(|s| {
+            let g = m.lock();
+            let t = s.spawn(|| *m.lock() += 1);
+            // the other thread gives up spinning and marks the lock contended
+            while m.state.load(Ordering::Relaxed) != CONTENDED {
+                thread::yield_now();
+            }
+            drop(g);
+            t.join().unwrap();
+        });
+        assert_eq!(m.into_inner(), 1);
+    }
+
+    #[test]
+    fn notify_one() {
+        let m = Mutex::new(None);
+        let cv = Condvar::new();
+        thread::scope(|s| {
+            s.spawn(|| {
+                thread::sleep(Duration::from_millis(10));
+                *m.lock() = Some(42);
+                cv.notify_one();
+            });
+            let mut g = m.lock();
+            while g.is_none() {
+                g = cv.wait(g);
+            }
+            assert_eq!(*g, Some(42));
+        });
+        // no waiters left, this returns without a syscall
+        cv.notify_one();
+    }
+
+    #[test]
+    fn notify_all() {
+        let m = Mutex::new((false, 0));
+        let cv = Condvar::new();
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    let mut g = cv.wait_while(m.lock(), |(go, _)| !*go);
+                    g.1 += 1;
+                });
+            }
+            // wait until they are all asleep, or at least registered
+            while cv.waiters.load(Ordering::Relaxed) < 4 {
+                thread::yield_now();
+            }
+            m.lock().0 = true;
+            cv.notify_all();
+        });
+        assert_eq!(m.into_inner(), (true, 4));
+    }
+
+    #[test]
+    fn wait_timeout() {
+        let m = Mutex::new(());
+        let cv = Condvar::new();
+        let start = Instant::now();
+        let (_g, r) = cv.wait_timeout(m.lock(), Duration::from_millis(20));
+        assert!(r.timed_out());
+        assert!(start.elapsed() >= Duration::from_millis(20));
+    }
+
+    #[test]
+    fn wait_timeout_notified() {
+        let m = Mutex::new(false);
+        let cv = Condvar::new();
+        thread::scope(|s| {
+            s.spawn(|| {
+                while cv.waiters.load(Ordering::Relaxed) == 0 {
+                    thread::yield_now();
+                }
+                *m.lock() = true;
+                cv.notify_one();
+            });
+            let mut g = m.lock();
+            while !*g {
+                let (g2, r) = cv.wait_timeout(g, Duration::from_secs(10));
+                assert!(!r.timed_out());
+                g = g2;
+            }
+        });
+    }
+}
@@ -14,12 +14,49 @@ fetch_* => the rest of it, fairly self explanatory
 
 */
 
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::cell::UnsafeCell;
+mod sync;
+use sync::{spin_loop, yield_now, AtomicBool, Ordering, UnsafeCell};
+
+mod spin;
+pub use spin::{SpinMutex, SpinMutexGuard};
+
+mod rwlock;
+pub use rwlock::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+mod ticket;
+pub use ticket::{TicketLock, TicketLockGuard};
+
+mod mcs;
+pub use mcs::{McsLock, McsLockGuard};
+
+#[cfg(all(target_os = "linux", not(feature = "loom")))]
+pub mod futex;
+
+pub mod epoch;
+pub mod stack;
+pub mod queue;
+pub mod counter;
+pub mod seqlock;
+
+// runs on the real CPU, loom has its own way of going through the outcomes
+#[cfg(not(feature = "loom"))]
+pub mod litmus;
+
+// reinterprets its memory as std atomics, which loom can't model
+#[cfg(not(feature = "loom"))]
+mod atomic_cell;
+#[cfg(not(feature = "loom"))]
+pub use atomic_cell::{AtomicCell, AtomicValue};
+
+#[cfg(all(test, feature = "loom"))]
+mod models;
 
 const LOCKED: bool = true;
 const UNLOCKED: bool = false;
 
+// one, two and three are the steps to a working spinlock, the finished one
+// is SpinMutex in spin.rs
+#[allow(dead_code)]
 mod one {
     use super::*;
 
@@ -41,7 +78,9 @@ mod one {
         
         // spinlock impl, dont use spinlocks in general! implementing just for exercise
         pub fn with_lock<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
-            while self.locked.load(Ordering::Relaxed) != UNLOCKED {}
+            while self.locked.load(Ordering::Relaxed) != UNLOCKED {
+                spin_loop();
+            }
             // in between the load and store here, another thread may run
             // std::thread::yield_now(); simulate thread getting pre-empted by OS
             self.locked.store(LOCKED, Ordering::Relaxed);
@@ -54,6 +93,7 @@ mod one {
     }
 }
 
+#[allow(dead_code)]
 mod two {
     use super::*;
 
@@ -122,9 +162,9 @@ mod two {
                 while self.locked.load(Ordering::Relaxed) == LOCKED {
                     // yield can be used to trigger race condition, letting othreads in here
                     // in order to show ordering::relaxed's problem
-                    std::thread::yield_now();
+                    yield_now();
                 }
-                std::thread::yield_now();
+                yield_now();
             } 
             // This still wouldn't work, though tests could be passing
             let ret = f(unsafe { &mut *self.v.get() });
@@ -137,7 +177,8 @@ mod two {
     }
 }
 
-mod three {
+// pub so the benches can compare against it
+pub mod three {
     use super::*;
 
     pub struct Mutex<T> {
@@ -193,9 +234,9 @@ mod three {
                 // in general can use Ordering::Relaxed if 
                 // when it doesn't matter what each thread sees
                 while self.locked.load(Ordering::Relaxed) == LOCKED {
-                    std::thread::yield_now();
+                    yield_now();
                 }
-                std::thread::yield_now();
+                yield_now();
             } 
             let ret = f(unsafe { &mut *self.v.get() });
             self.locked.store(UNLOCKED, Ordering::Release);
@@ -227,22 +268,27 @@ mod three {
 // Can be used to give unique sequence numbers to a bunch of things that happens
 // concurrently
 
-#[cfg(test)]
+#[cfg(all(test, not(feature = "loom")))]
 mod tests {
     use super::*;
     use std::thread::spawn;
 
+    // This used to be one_does_not_work, run against one::Mutex, which loses
+    // increments when two threads both see the lock as free and both take it
     #[test]
-    fn one_does_not_work() {
-        let l: &'static _ = Box::leak(Box::new(one::Mutex::new(0)));
+    fn spin_mutex_stress() {
+        let l: &'static _ = Box::leak(Box::new(SpinMutex::new(0)));
         let handles: Vec<_> = (0..10)
             .map(|_| {
                 spawn(move || {
-                    for _ in 0..100 {
-                        l.with_lock(|v| {
+                    for _ in 0..10000 {
+                        *l.lock() += 1;
+                        // mix in some try_locks, they must not lose increments either
+                        if let Some(mut v) = l.try_lock() {
                             *v += 1;
-                        })
-                    }   
+                            *v -= 1;
+                        }
+                    }
                 })
             })
             .collect();
@@ -250,7 +296,7 @@ mod tests {
         for handle in handles {
             handle.join().unwrap();
         }
-        assert_eq!(l.with_lock(|v| *v), 10 * 100);
+        assert_eq!(*l.lock(), 10 * 10000);
     }
 
     #[test]
@@ -301,8 +347,10 @@ mod tests {
              */
         });
 
-        let r1 = t1.join().unwrap();
-        let r2 = t2.join().unwrap();
+        // run once, this shows nothing. litmus.rs runs LB (the same shape,
+        // y = 42 is y = 1) a few million times and counts the outcomes
+        let _r1 = t1.join().unwrap();
+        let _r2 = t2.join().unwrap();
         
 
     }
@@ -350,7 +398,8 @@ mod tests {
         t1.join().unwrap();
         t2.join().unwrap();
 
-        let z = z.load(Ordering::SeqCst);
+        // Same here, litmus.rs has this as IRIW (without the spinning)
+        let _z = z.load(Ordering::SeqCst);
         /*
         What are the possible values for z?
 
@@ -397,7 +446,8 @@ mod tests {
         Can use ThreadSanitizer to check for atomic bugs, even in Rust. 
 
         Use Loom for testing. in testing use Loom structs and in production use
-        std lib structs. 
+        std lib structs.
+        (that's what the loom feature and sync.rs do, see models.rs)
 
         std::sync::atomic::compiler_fence is only for the compiler, but the CPU
         can still execute out of order, so rarely used
@@ -0,0 +1,403 @@
+// Litmus tests: tiny programs, two to four threads of one or two accesses
+// each, whose outcomes tell you what reorderings the memory model (and this
+// CPU) allows. too_relaxed and test_seq_cst in lib.rs are two of them, run
+// once each. That tells you nothing, the interesting outcomes show up maybe
+// once in a million runs, so here they are run over and over and every
+// outcome is counted.
+//
+// The classic shapes, x and y start at 0, rN are loads:
+//
+//   SB (store buffering)       x = 1; r0 = y     ||  y = 1; r1 = x
+//   MP (message passing)       x = 1; y = 1      ||  r0 = y; r1 = x
+//   LB (load buffering)        r0 = x; y = 1     ||  r1 = y; x = 1
+//   IRIW (independent reads    x = 1  ||  y = 1  ||  r0 = x; r1 = y
+//     of independent writes)                     ||  r2 = y; r3 = x
+//   2+2W                       x = 1; y = 2      ||  y = 1; x = 2
+//                              (outcome is x and y once both are done)
+//
+// Every store in a run uses the same ordering, and so does every load, and
+// every pair of them is tried. FORBIDDEN lists which outcomes the Rust (C++)
+// memory model rules out for which orderings. Seeing one of those means a bug
+// in the compiler or CPU, or in the table. The outcomes that are allowed but
+// weird (r0 = 0, r1 = 0 in SB with Release/Acquire) may or may not show up,
+// x86 for example never reorders two stores, so MP with Relaxed is always
+// fine there, but it does buffer stores, so SB isn't.
+//
+//   cargo bench --bench litmus
+use crate::counter::CachePadded;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Shape {
+    SB,
+    MP,
+    LB,
+    IRIW,
+    TwoPlusTwoW,
+}
+
+impl Shape {
+    pub const ALL: [Shape; 5] = [
+        Shape::SB,
+        Shape::MP,
+        Shape::LB,
+        Shape::IRIW,
+        Shape::TwoPlusTwoW,
+    ];
+
+    fn threads(self) -> usize {
+        match self {
+            Shape::IRIW => 4,
+            _ => 2,
+        }
+    }
+
+    // what each number in an outcome is
+    fn names(self) -> &'static [&'static str] {
+        match self {
+            Shape::IRIW => &["r0", "r1", "r2", "r3"],
+            Shape::TwoPlusTwoW => &["x", "y"],
+            _ => &["r0", "r1"],
+        }
+    }
+
+    // 2+2W has no loads until the end, and those are after the join
+    fn has_loads(self) -> bool {
+        self != Shape::TwoPlusTwoW
+    }
+}
+
+impl fmt::Display for Shape {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Shape::TwoPlusTwoW => f.pad("2+2W"),
+            s => f.pad(&format!("{:?}", s)),
+        }
+    }
+}
+
+// Which orderings make an outcome impossible
+#[derive(Debug, Clone, Copy)]
+enum Needs {
+    // every access SeqCst, there is then one order of them all that every
+    // thread agrees on
+    SeqCst,
+    // stores at least Release, loads at least Acquire, so a load that sees a
+    // store synchronizes with it
+    ReleaseAcquire,
+}
+
+// The outcomes the memory model forbids, and what it takes to forbid them
+const FORBIDDEN: &[(Shape, Needs, &[usize])] = &[
+    // both loads ran before both stores, but each load comes after a store
+    (Shape::SB, Needs::SeqCst, &[0, 0]),
+    // saw the flag but not the message
+    (Shape::MP, Needs::ReleaseAcquire, &[1, 0]),
+    // each load saw the store that comes after the other load
+    (Shape::LB, Needs::ReleaseAcquire, &[1, 1]),
+    // the readers disagree on which store was first (test_seq_cst's z == 0)
+    (Shape::IRIW, Needs::SeqCst, &[1, 0, 1, 0]),
+    // each thread's first store came last
+    (Shape::TwoPlusTwoW, Needs::SeqCst, &[1, 1]),
+];
+
+fn at_least_release(o: Ordering) -> bool {
+    matches!(o, Ordering::Release | Ordering::SeqCst)
+}
+
+fn at_least_acquire(o: Ordering) -> bool {
+    matches!(o, Ordering::Acquire | Ordering::SeqCst)
+}
+
+// The outcome the model rules out for this shape with these orderings, if any
+pub fn forbidden(shape: Shape, store: Ordering, load: Ordering) -> Option<&'static [usize]> {
+    let (_, needs, outcome) = FORBIDDEN.iter().find(|(s, ..)| *s == shape)?;
+    // 2+2W has no loads, only its stores count
+    let load_sc = !shape.has_loads() || load == Ordering::SeqCst;
+    let load_acq = !shape.has_loads() || at_least_acquire(load);
+    let applies = match needs {
+        Needs::SeqCst => store == Ordering::SeqCst && load_sc,
+        Needs::ReleaseAcquire => at_least_release(store) && load_acq,
+    };
+    applies.then_some(*outcome)
+}
+
+// Every store and load ordering pair that makes sense (a store can't be
+// Acquire, a load can't be Release). For 2+2W the load ordering is unused, so
+// it only gets Relaxed
+pub fn combinations(shape: Shape) -> Vec<(Ordering, Ordering)> {
+    let loads: &[Ordering] = if shape.has_loads() {
+        &[Ordering::Relaxed, Ordering::Acquire, Ordering::SeqCst]
+    } else {
+        &[Ordering::Relaxed]
+    };
+    [Ordering::Relaxed, Ordering::Release, Ordering::SeqCst]
+        .into_iter()
+        .flat_map(|s| loads.iter().map(move |&l| (s, l)))
+        .collect()
+}
+
+pub struct Report {
+    pub shape: Shape,
+    pub store: Ordering,
+    pub load: Ordering,
+    pub runs: usize,
+    // how often each outcome came up
+    pub histogram: BTreeMap<Vec<usize>, usize>,
+}
+
+impl Report {
+    pub fn forbidden(&self) -> Option<&'static [usize]> {
+        forbidden(self.shape, self.store, self.load)
+    }
+
+    // How many runs ended in the forbidden outcome, should always be 0
+    pub fn forbidden_seen(&self) -> usize {
+        self.forbidden()
+            .and_then(|f| self.histogram.get(f))
+            .copied()
+            .unwrap_or(0)
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Ordering's Debug ignores the width, so format it first
+        let store = format!("{:?}", self.store);
+        write!(f, "{:<5} store {:<7}", self.shape, store)?;
+        if self.shape.has_loads() {
+            write!(f, " load {:<7}", format!("{:?}", self.load))?;
+        }
+        writeln!(f, " {} runs", self.runs)?;
+
+        let outcome = |o: &[usize]| {
+            let regs: Vec<String> = self
+                .shape
+                .names()
+                .iter()
+                .zip(o)
+                .map(|(n, v)| format!("{}={}", n, v))
+                .collect();
+            regs.join(" ")
+        };
+        let forbidden = self.forbidden();
+        for (o, n) in &self.histogram {
+            write!(f, "    {:<20} {:>10}", outcome(o), n)?;
+            if Some(&o[..]) == forbidden {
+                write!(f, "  FORBIDDEN")?;
+            }
+            writeln!(f)?;
+        }
+        // show that we were looking for it
+        if let Some(o) = forbidden.filter(|o| !self.histogram.contains_key(*o)) {
+            writeln!(f, "    {:<20} {:>10}  forbidden", outcome(o), 0)?;
+        }
+        Ok(())
+    }
+}
+
+// One instance of the test, x and y on their own cache lines so the threads
+// don't slow each other down more than the test itself does
+#[derive(Default)]
+struct Instance {
+    x: CachePadded<AtomicUsize>,
+    y: CachePadded<AtomicUsize>,
+}
+
+// What thread t of the shape does, returns the values it loaded
+fn thread_body(
+    shape: Shape,
+    t: usize,
+    i: &Instance,
+    store: Ordering,
+    load: Ordering,
+) -> [usize; 2] {
+    let (x, y) = (&i.x, &i.y);
+    match (shape, t) {
+        (Shape::SB, 0) => {
+            x.store(1, store);
+            [y.load(load), 0]
+        }
+        (Shape::SB, _) => {
+            y.store(1, store);
+            [x.load(load), 0]
+        }
+        (Shape::MP, 0) => {
+            x.store(1, store);
+            y.store(1, store);
+            [0, 0]
+        }
+        (Shape::MP, _) => {
+            let r0 = y.load(load);
+            [r0, x.load(load)]
+        }
+        (Shape::LB, 0) => {
+            let r0 = x.load(load);
+            y.store(1, store);
+            [r0, 0]
+        }
+        (Shape::LB, _) => {
+            let r1 = y.load(load);
+            x.store(1, store);
+            [r1, 0]
+        }
+        (Shape::IRIW, 0) => {
+            x.store(1, store);
+            [0, 0]
+        }
+        (Shape::IRIW, 1) => {
+            y.store(1, store);
+            [0, 0]
+        }
+        (Shape::IRIW, 2) => {
+            let r0 = x.load(load);
+            [r0, y.load(load)]
+        }
+        (Shape::IRIW, _) => {
+            let r2 = y.load(load);
+            [r2, x.load(load)]
+        }
+        (Shape::TwoPlusTwoW, 0) => {
+            x.store(1, store);
+            y.store(2, store);
+            [0, 0]
+        }
+        (Shape::TwoPlusTwoW, _) => {
+            y.store(1, store);
+            x.store(2, store);
+            [0, 0]
+        }
+    }
+}
+
+// Put together what the threads loaded (and the final x and y) into the outcome
+fn outcome(shape: Shape, regs: &[[usize; 2]], i: &Instance) -> Vec<usize> {
+    match shape {
+        Shape::SB | Shape::LB => vec![regs[0][0], regs[1][0]],
+        Shape::MP => regs[1].to_vec(),
+        Shape::IRIW => vec![regs[2][0], regs[2][1], regs[3][0], regs[3][1]],
+        Shape::TwoPlusTwoW => vec![i.x.load(Ordering::Relaxed), i.y.load(Ordering::Relaxed)],
+    }
+}
+
+// Spawning threads for every run would take far longer than the run, and the
+// threads would hardly ever overlap. Instead each thread runs its part of a
+// whole batch of instances, one after the other, after all of them have
+// started. The threads go at about the same speed, so they mostly work on the
+// same instance at the same time
+const BATCH: usize = 10_000;
+
+pub fn run(shape: Shape, store: Ordering, load: Ordering, runs: usize) -> Report {
+    let mut histogram = BTreeMap::new();
+    let mut done = 0;
+    while done < runs {
+        let n = BATCH.min(runs - done);
+        let instances: Vec<Instance> = (0..n).map(|_| Instance::default()).collect();
+        let started = AtomicUsize::new(0);
+        let regs: Vec<Vec<[usize; 2]>> = thread::scope(|s| {
+            let handles: Vec<_> = (0..shape.threads())
+                .map(|t| {
+                    let (instances, started) = (&instances, &started);
+                    s.spawn(move || {
+                        started.fetch_add(1, Ordering::Relaxed);
+                        while started.load(Ordering::Relaxed) < shape.threads() {
+                            std::hint::spin_loop();
+                        }
+                        instances
+                            .iter()
+                            .map(|i| thread_body(shape, t, i, store, load))
+                            .collect()
+                    })
+                })
+                .collect();
+            handles.into_iter().map(|h| h.join().unwrap()).collect()
+        });
+        for (k, i) in instances.iter().enumerate() {
+            let per_thread: Vec<[usize; 2]> = regs.iter().map(|r| r[k]).collect();
+            *histogram.entry(outcome(shape, &per_thread, i)).or_insert(0) += 1;
+        }
+        done += n;
+    }
+    Report {
+        shape,
+        store,
+        load,
+        runs,
+        histogram,
+    }
+}
+
+// Every shape under every ordering combination
+pub fn run_all(runs: usize) -> Vec<Report> {
+    Shape::ALL
+        .into_iter()
+        .flat_map(|shape| {
+            combinations(shape)
+                .into_iter()
+                .map(move |(store, load)| run(shape, store, load, runs))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{combinations, forbidden, run, run_all, Shape};
+    use std::sync::atomic::Ordering::*;
+
+    #[test]
+    fn forbidden_table() {
+        assert_eq!(forbidden(Shape::SB, SeqCst, SeqCst), Some(&[0, 0][..]));
+        assert_eq!(forbidden(Shape::SB, Release, Acquire), None);
+        assert_eq!(forbidden(Shape::MP, Release, Acquire), Some(&[1, 0][..]));
+        assert_eq!(forbidden(Shape::MP, SeqCst, Acquire), Some(&[1, 0][..]));
+        assert_eq!(forbidden(Shape::MP, Release, Relaxed), None);
+        assert_eq!(forbidden(Shape::MP, Relaxed, SeqCst), None);
+        assert_eq!(forbidden(Shape::LB, Relaxed, Relaxed), None);
+        assert_eq!(forbidden(Shape::IRIW, SeqCst, Acquire), None);
+        assert_eq!(
+            forbidden(Shape::IRIW, SeqCst, SeqCst),
+            Some(&[1, 0, 1, 0][..])
+        );
+        assert_eq!(
+            forbidden(Shape::TwoPlusTwoW, SeqCst, Relaxed),
+            Some(&[1, 1][..])
+        );
+        assert_eq!(forbidden(Shape::TwoPlusTwoW, Release, Relaxed), None);
+        assert_eq!(combinations(Shape::SB).len(), 9);
+        assert_eq!(combinations(Shape::TwoPlusTwoW).len(), 3);
+    }
+
+    #[test]
+    fn histogram() {
+        let r = run(Shape::MP, Release, Acquire, 25_000);
+        assert_eq!(r.histogram.values().sum::<usize>(), 25_000);
+        // every outcome is one of the four possible ones
+        assert!(r
+            .histogram
+            .keys()
+            .all(|o| o.len() == 2 && o.iter().all(|&v| v <= 1)));
+        let shown = r.to_string();
+        assert!(shown.starts_with("MP    store Release load Acquire 25000 runs\n"));
+        assert!(shown.contains("r0=1 r1=0"));
+    }
+
+    // The actual litmus test: fails if any forbidden outcome ever shows up.
+    // The bench runs the same with many more runs
+    #[test]
+    fn nothing_forbidden() {
+        let reports = run_all(20_000);
+        let bad: Vec<String> = reports
+            .iter()
+            .filter(|r| r.forbidden_seen() > 0)
+            .map(|r| r.to_string())
+            .collect();
+        assert!(
+            bad.is_empty(),
+            "forbidden outcomes seen:\n{}",
+            bad.join("\n")
+        );
+    }
+}
@@ -0,0 +1,261 @@
+// The MCS lock (Mellor-Crummey and Scott), a ticket lock where every waiter
+// spins on its own flag instead of one shared counter.
+//
+// Each thread that wants the lock brings a node, and swaps it in as the tail
+// of a queue. If there was a tail before, it links itself behind it and spins
+// on its own node's locked flag. Unlocking hands the lock to the next node by
+// clearing its flag, which only touches that one waiter's cache line. With a
+// ticket lock every unlock invalidates the line all waiters are spinning on,
+// which gets expensive with many cores.
+//
+// Same as the ticket lock, waiters get the lock in the order they arrived.
+//
+// The C version has the caller pass in the node, usually on its stack. Here
+// the guard has to be movable, so the node is boxed instead. Taking the lock
+// allocates, which is fine for comparing locks but is why the real ones
+// (Linux's qspinlock) keep a few nodes per CPU
+use crate::spin::Backoff;
+use crate::sync::{maybe_const_fn, AtomicBool, AtomicPtr, Ordering, UnsafeCell};
+use std::fmt;
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
+use std::ptr;
+
+pub struct McsLock<T> {
+    // the last node in the queue, null while nobody holds the lock
+    tail: AtomicPtr<Node>,
+    v: UnsafeCell<T>,
+}
+
+struct Node {
+    // cleared by the node ahead of us when it hands the lock over
+    locked: AtomicBool,
+    // the node behind us, filled in by it once it has swapped itself in
+    next: AtomicPtr<Node>,
+}
+
+// Same as SpinMutex
+unsafe impl<T> Sync for McsLock<T> where T: Send {}
+
+pub struct McsLockGuard<'a, T> {
+    lock: &'a McsLock<T>,
+    // our node, freed when we hand the lock over
+    node: *mut Node,
+    // see SpinMutexGuard. Also needed for Send, node is a raw pointer
+    _marker: PhantomData<&'a mut T>,
+}
+
+// The node isn't tied to a thread, whoever drops the guard unlocks
+unsafe impl<T> Send for McsLockGuard<'_, T> where T: Send {}
+unsafe impl<T> Sync for McsLockGuard<'_, T> where T: Sync {}
+
+impl Node {
+    fn alloc() -> *mut Node {
+        Box::into_raw(Box::new(Node {
+            locked: AtomicBool::new(true),
+            next: AtomicPtr::new(ptr::null_mut()),
+        }))
+    }
+}
+
+impl<T> McsLock<T> {
+    maybe_const_fn! {
+        pub fn new(t: T) -> Self {
+            Self {
+                tail: AtomicPtr::new(ptr::null_mut()),
+                v: UnsafeCell::new(t),
+            }
+        }
+    }
+
+    pub fn lock(&self) -> McsLockGuard<'_, T> {
+        let node = Node::alloc();
+        // Release so the node behind us sees our node initialised. Acquire so
+        // we see the node ahead of us initialised, and if the queue was
+        // empty, pairs with the Release in unlock when it emptied it
+        let prev = self.tail.swap(node, Ordering::AcqRel);
+        if !prev.is_null() {
+            // SAFETY: prev can't hand the lock over (and free its node)
+            // before it has seen us in its next, see the guard's drop
+            unsafe { (*prev).next.store(node, Ordering::Release) };
+            let mut backoff = Backoff::new();
+            // SAFETY: our node, only freed by us
+            // Acquire pairs with the Release that hands us the lock
+            while unsafe { (*node).locked.load(Ordering::Acquire) } {
+                backoff.snooze();
+            }
+        }
+        McsLockGuard {
+            lock: self,
+            node,
+            _marker: PhantomData,
+        }
+    }
+
+    // Takes the lock only if the queue is empty
+    pub fn try_lock(&self) -> Option<McsLockGuard<'_, T>> {
+        // Most failed attempts find the lock taken, don't allocate a node just
+        // to free it again. The compare_exchange below is what decides
+        if !self.tail.load(Ordering::Relaxed).is_null() {
+            return None;
+        }
+        let node = Node::alloc();
+        // AcqRel for the same reasons as the swap in lock
+        match self
+            .tail
+            .compare_exchange(ptr::null_mut(), node, Ordering::AcqRel, Ordering::Relaxed)
+        {
+            Ok(_) => Some(McsLockGuard {
+                lock: self,
+                node,
+                _marker: PhantomData,
+            }),
+            Err(_) => {
+                // SAFETY: never shared
+                drop(unsafe { Box::from_raw(node) });
+                None
+            }
+        }
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.v.get_mut()
+    }
+
+    pub fn into_inner(self) -> T {
+        self.v.into_inner()
+    }
+}
+
+impl<T: Default> Default for McsLock<T> {
+    fn default() -> Self {
+        McsLock::new(T::default())
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for McsLock<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut d = f.debug_struct("McsLock");
+        match self.try_lock() {
+            Some(guard) => d.field("data", &&*guard),
+            None => d.field("data", &format_args!("<locked>")),
+        };
+        d.finish_non_exhaustive()
+    }
+}
+
+impl<T> Deref for McsLockGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        // SAFETY: we hold the lock
+        unsafe { &*self.lock.v.get() }
+    }
+}
+
+impl<T> DerefMut for McsLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        // SAFETY: we hold the lock, and &mut self stops the guard handing out
+        // two of these
+        unsafe { &mut *self.lock.v.get() }
+    }
+}
+
+impl<T> Drop for McsLockGuard<'_, T> {
+    fn drop(&mut self) {
+        // SAFETY: our node stays alive until the end of this function
+        let node = unsafe { &*self.node };
+        let mut next = node.next.load(Ordering::Acquire);
+        if next.is_null() {
+            // Nobody behind us that we know of. If we are still the tail
+            // the queue is empty now. Release so the next lock sees our
+            // changes to v
+            if self
+                .lock
+                .tail
+                .compare_exchange(
+                    self.node,
+                    ptr::null_mut(),
+                    Ordering::Release,
+                    Ordering::Relaxed,
+                )
+                .is_ok()
+            {
+                // SAFETY: not in the queue any more, nobody else can get to it
+                drop(unsafe { Box::from_raw(self.node) });
+                return;
+            }
+            // Someone has swapped themselves in behind us, but hasn't linked
+            // up yet. They will in a moment, and we can't free our node before
+            // they have
+            let mut backoff = Backoff::new();
+            loop {
+                next = node.next.load(Ordering::Acquire);
+                if !next.is_null() {
+                    break;
+                }
+                backoff.snooze();
+            }
+        }
+        // Hand over. Release so the next thread sees our changes to v
+        // SAFETY: next only frees its node after it has the lock
+        unsafe { (*next).locked.store(false, Ordering::Release) };
+        // SAFETY: next has already written to our node, nobody else will
+        drop(unsafe { Box::from_raw(self.node) });
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for McsLockGuard<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+#[cfg(all(test, not(feature = "loom")))]
+mod test {
+    use super::McsLock;
+    use std::sync::atomic::Ordering;
+    use std::thread;
+
+    #[test]
+    fn guard_and_try_lock() {
+        let l = McsLock::new(vec![1]);
+        let mut g = l.lock();
+        g.push(2);
+        assert!(l.try_lock().is_none());
+        assert_eq!(format!("{:?}", l), "McsLock { data: <locked>, .. }");
+        drop(g);
+        assert_eq!(*l.try_lock().unwrap(), [1, 2]);
+        assert_eq!(format!("{:?}", l), "McsLock { data: [1, 2], .. }");
+        assert!(l.tail.load(Ordering::Relaxed).is_null());
+        assert_eq!(l.into_inner(), [1, 2]);
+    }
+
+    #[test]
+    fn guard_can_move_threads() {
+        let l = McsLock::new(0);
+        let mut g = l.lock();
+        thread::scope(|s| {
+            s.spawn(move || *g += 1);
+        });
+        assert_eq!(*l.lock(), 1);
+    }
+
+    #[test]
+    fn stress() {
+        let mut l = McsLock::new(0);
+        thread::scope(|s| {
+            for _ in 0..8 {
+                s.spawn(|| {
+                    for _ in 0..10000 {
+                        *l.lock() += 1;
+                        if let Some(mut g) = l.try_lock() {
+                            *g += 1;
+                        }
+                    }
+                });
+            }
+        });
+        assert!(*l.get_mut() >= 8 * 10000);
+        assert!(l.tail.load(Ordering::Relaxed).is_null());
+    }
+}
@@ -0,0 +1,266 @@
+// The Michael-Scott queue, a lock-free FIFO for any number of producers and
+// consumers. Like the Treiber stack it is a linked list where every change is
+// one compare_exchange, but with two ends to swing:
+//
+//   head -> sentinel -> a -> b -> c <- tail
+//
+// head always points at a sentinel node whose value is gone (or was never
+// there), the first real value is in the node after it. Popping moves head
+// one node on, and that node becomes the new sentinel. So head and tail never
+// have to be updated together, an empty queue is just the sentinel with both
+// pointing at it.
+//
+// Pushing is two steps, linking the node onto tail's next and then moving tail
+// to it. Another thread can come in between the two, so whoever finds tail
+// lagging (tail's next isn't null) moves it on before doing anything else.
+// Nobody ever waits for the thread that linked the node to finish.
+//
+// Unlinked sentinels go to the epoch Collector, see epoch.rs
+use crate::epoch::Collector;
+use crate::sync::{AtomicPtr, Ordering};
+use std::mem::MaybeUninit;
+use std::ptr;
+
+pub struct MsQueue<T> {
+    head: AtomicPtr<Node<T>>,
+    tail: AtomicPtr<Node<T>>,
+    collector: Collector,
+}
+
+struct Node<T> {
+    // uninit in the first sentinel, and moved out when a node becomes one
+    value: MaybeUninit<T>,
+    next: AtomicPtr<Node<T>>,
+}
+
+impl<T> Node<T> {
+    fn alloc(value: MaybeUninit<T>) -> *mut Node<T> {
+        Box::into_raw(Box::new(Node {
+            value,
+            next: AtomicPtr::new(ptr::null_mut()),
+        }))
+    }
+}
+
+// Same as TreiberStack, every value goes to exactly one thread
+unsafe impl<T: Send> Send for MsQueue<T> {}
+unsafe impl<T: Send> Sync for MsQueue<T> {}
+
+impl<T> MsQueue<T> {
+    pub fn new() -> Self {
+        let sentinel = Node::alloc(MaybeUninit::uninit());
+        MsQueue {
+            head: AtomicPtr::new(sentinel),
+            tail: AtomicPtr::new(sentinel),
+            collector: Collector::new(),
+        }
+    }
+
+    pub fn push(&self, value: T) {
+        let node = Node::alloc(MaybeUninit::new(value));
+        // Unlike the stack we do look inside a node someone else may pop
+        let _guard = self.collector.pin();
+        loop {
+            // Acquire pairs with the Release of whoever linked tail in, so
+            // its next is initialised
+            let tail = self.tail.load(Ordering::Acquire);
+            // SAFETY: we are pinned, and tail never falls behind head (see
+            // try_pop), so the node hasn't been freed
+            let next = unsafe { &(*tail).next };
+            let n = next.load(Ordering::Acquire);
+            if !n.is_null() {
+                // tail is lagging, help move it on. If it fails someone else
+                // already did
+                let _ = self
+                    .tail
+                    .compare_exchange(tail, n, Ordering::Release, Ordering::Relaxed);
+                continue;
+            }
+            // Release so a pop that Acquires next sees the value. This is the
+            // point where the value is in the queue
+            if next
+                .compare_exchange(ptr::null_mut(), node, Ordering::Release, Ordering::Relaxed)
+                .is_ok()
+            {
+                // Failing is fine, another thread found tail lagging and moved
+                // it on for us
+                let _ =
+                    self.tail
+                        .compare_exchange(tail, node, Ordering::Release, Ordering::Relaxed);
+                return;
+            }
+        }
+    }
+
+    pub fn try_pop(&self) -> Option<T> {
+        let guard = self.collector.pin();
+        loop {
+            let head = self.head.load(Ordering::Acquire);
+            // SAFETY: we are pinned, so head hasn't been freed even if another
+            // pop has moved past it since
+            let next = unsafe { (*head).next.load(Ordering::Acquire) };
+            if next.is_null() {
+                return None;
+            }
+            // If tail is still at the sentinel, the push that linked next
+            // hasn't moved it yet. Do it for them: once head moves on the old
+            // sentinel is freed, and tail must never point at a freed node
+            let tail = self.tail.load(Ordering::Acquire);
+            if head == tail {
+                let _ =
+                    self.tail
+                        .compare_exchange(tail, next, Ordering::Release, Ordering::Relaxed);
+                continue;
+            }
+            if self
+                .head
+                .compare_exchange(head, next, Ordering::Acquire, Ordering::Relaxed)
+                .is_ok()
+            {
+                // SAFETY: next is the new sentinel, and moving head to it is
+                // what gave us its value, no other pop will read it. The old
+                // sentinel is unlinked, but other threads may still be looking
+                // at its next
+                unsafe {
+                    let value = ptr::read(&(*next).value).assume_init();
+                    guard.defer_destroy(head);
+                    return Some(value);
+                }
+            }
+        }
+    }
+
+    // Only a snapshot, another thread may push or pop right after
+    pub fn is_empty(&self) -> bool {
+        let _guard = self.collector.pin();
+        let head = self.head.load(Ordering::Acquire);
+        // SAFETY: pinned, see try_pop
+        unsafe { (*head).next.load(Ordering::Acquire).is_null() }
+    }
+}
+
+impl<T> Default for MsQueue<T> {
+    fn default() -> Self {
+        MsQueue::new()
+    }
+}
+
+impl<T> Drop for MsQueue<T> {
+    fn drop(&mut self) {
+        // &mut self, nobody else is using the queue. The sentinel has no
+        // value, every node after it does
+        let mut p = self.head.load(Ordering::Relaxed);
+        let mut sentinel = true;
+        while !p.is_null() {
+            // SAFETY: every node came from Box::into_raw in Node::alloc, and
+            // the ones still linked haven't been given to the collector
+            let mut node = unsafe { Box::from_raw(p) };
+            if !sentinel {
+                // SAFETY: only the sentinel's value has been moved out
+                unsafe { node.value.assume_init_drop() };
+            }
+            sentinel = false;
+            p = node.next.load(Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(all(test, not(feature = "loom")))]
+mod test {
+    use super::MsQueue;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+
+    #[test]
+    fn fifo() {
+        let q = MsQueue::new();
+        assert!(q.is_empty());
+        assert_eq!(q.try_pop(), None);
+        q.push(1);
+        q.push(2);
+        assert!(!q.is_empty());
+        assert_eq!(q.try_pop(), Some(1));
+        q.push(3);
+        assert_eq!(q.try_pop(), Some(2));
+        assert_eq!(q.try_pop(), Some(3));
+        assert_eq!(q.try_pop(), None);
+        assert!(q.is_empty());
+    }
+
+    #[test]
+    fn drops_every_value_once() {
+        struct D<'a>(&'a AtomicUsize);
+        impl Drop for D<'_> {
+            fn drop(&mut self) {
+                self.0.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        let drops = AtomicUsize::new(0);
+        let q = MsQueue::new();
+        for _ in 0..10 {
+            q.push(D(&drops));
+        }
+        for _ in 0..4 {
+            drop(q.try_pop());
+        }
+        assert_eq!(drops.load(Ordering::Relaxed), 4);
+        drop(q);
+        assert_eq!(drops.load(Ordering::Relaxed), 10);
+    }
+
+    #[test]
+    fn mpmc() {
+        const PRODUCERS: usize = 4;
+        const CONSUMERS: usize = 4;
+        const N: usize = 20000;
+        let q = MsQueue::new();
+        let done = AtomicUsize::new(0);
+        let popped: Vec<Vec<(usize, usize)>> = thread::scope(|s| {
+            for p in 0..PRODUCERS {
+                let (q, done) = (&q, &done);
+                s.spawn(move || {
+                    for i in 0..N {
+                        q.push((p, i));
+                    }
+                    done.fetch_add(1, Ordering::Release);
+                });
+            }
+            let consumers: Vec<_> = (0..CONSUMERS)
+                .map(|_| {
+                    s.spawn(|| {
+                        let mut popped = Vec::new();
+                        loop {
+                            // read done first, if everyone was done before an
+                            // empty try_pop, nothing is left to come
+                            let finished = done.load(Ordering::Acquire) == PRODUCERS;
+                            match q.try_pop() {
+                                Some(v) => popped.push(v),
+                                None if finished => return popped,
+                                None => thread::yield_now(),
+                            }
+                        }
+                    })
+                })
+                .collect();
+            consumers.into_iter().map(|c| c.join().unwrap()).collect()
+        });
+
+        // each consumer sees each producer's values in the order they were
+        // pushed
+        for c in &popped {
+            for p in 0..PRODUCERS {
+                let mine: Vec<_> = c.iter().filter(|v| v.0 == p).map(|v| v.1).collect();
+                assert!(mine.windows(2).all(|w| w[0] < w[1]));
+            }
+        }
+        // and every value came out exactly once
+        let mut all: Vec<_> = popped.into_iter().flatten().collect();
+        all.sort();
+        let expected: Vec<_> = (0..PRODUCERS)
+            .flat_map(|p| (0..N).map(move |i| (p, i)))
+            .collect();
+        assert_eq!(all, expected);
+        assert!(q.is_empty());
+    }
+}
@@ -0,0 +1,347 @@
+// Any number of readers, or one writer. A Mutex makes readers wait for each
+// other for no reason, which hurts when nearly everything is a read.
+//
+// Everything lives in one AtomicU32, so taking or releasing the lock is always
+// a single atomic operation:
+//
+//   state = 2 * readers + writer_waiting    while readers hold the lock
+//   state = u32::MAX                        while a writer holds it
+//
+// writer_waiting (the lowest bit) is how writers get preference. A steady
+// stream of readers could otherwise keep readers > 0 forever, and the writer
+// would never get in. Once a writer sets the bit, new readers wait, the
+// readers already inside finish, and the writer goes next.
+//
+// Waiting threads spin with the same Backoff as SpinMutex
+use crate::spin::Backoff;
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+use crate::sync::{maybe_const_fn, AtomicU32, Ordering, UnsafeCell};
+
+const WRITE_LOCKED: u32 = u32::MAX;
+const WRITER_WAITING: u32 = 1;
+const READER: u32 = 2;
+// One more reader would leave room for nothing but the waiting bit, and a
+// writer setting it would make the state look write locked
+const MAX_READ_LOCKED: u32 = WRITE_LOCKED - READER - WRITER_WAITING;
+
+pub struct RwLock<T> {
+    state: AtomicU32,
+    v: UnsafeCell<T>,
+}
+
+// Readers on different threads all get a &T at the same time, so unlike
+// Mutex, T has to be Sync as well as Send
+unsafe impl<T> Sync for RwLock<T> where T: Send + Sync {}
+
+pub struct RwLockReadGuard<'a, T> {
+    lock: &'a RwLock<T>,
+}
+
+pub struct RwLockWriteGuard<'a, T> {
+    lock: &'a RwLock<T>,
+}
+
+impl<T> RwLock<T> {
+    maybe_const_fn! {
+        pub fn new(t: T) -> Self {
+            Self {
+                state: AtomicU32::new(0),
+                v: UnsafeCell::new(t),
+            }
+        }
+    }
+
+    pub fn read(&self) -> RwLockReadGuard<'_, T> {
+        let mut backoff = Backoff::new();
+        let mut s = self.state.load(Ordering::Relaxed);
+        loop {
+            // no writer holding the lock (that sets every bit) or waiting
+            if s & WRITER_WAITING == 0 {
+                assert!(s < MAX_READ_LOCKED, "too many readers");
+                // Acquire on success pairs with the Release in the write
+                // guard's drop, so we see everything the last writer did. No
+                // need to see what other readers did, they didn't change
+                // anything. Failing gives us nothing, so Relaxed
+                match self.state.compare_exchange_weak(
+                    s,
+                    s + READER,
+                    Ordering::Acquire,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => return RwLockReadGuard { lock: self },
+                    Err(e) => s = e,
+                }
+            } else {
+                backoff.snooze();
+                s = self.state.load(Ordering::Relaxed);
+            }
+        }
+    }
+
+    // Fails if a writer holds the lock or is waiting for it
+    pub fn try_read(&self) -> Option<RwLockReadGuard<'_, T>> {
+        let mut s = self.state.load(Ordering::Relaxed);
+        // Only loops while other readers come and go, we never wait here
+        while s & WRITER_WAITING == 0 {
+            assert!(s < MAX_READ_LOCKED, "too many readers");
+            match self
+                .state
+                .compare_exchange(s, s + READER, Ordering::Acquire, Ordering::Relaxed)
+            {
+                Ok(_) => return Some(RwLockReadGuard { lock: self }),
+                Err(e) => s = e,
+            }
+        }
+        None
+    }
+
+    pub fn write(&self) -> RwLockWriteGuard<'_, T> {
+        let mut backoff = Backoff::new();
+        let mut s = self.state.load(Ordering::Relaxed);
+        loop {
+            // No readers left. Take it even if the waiting bit is set, it is
+            // either ours or belongs to another writer that will set it again
+            if s <= WRITER_WAITING {
+                // Acquire pairs with the Release in both guards' drop: we
+                // need to see the last writer's changes, and the readers must
+                // be done reading before we start writing
+                match self.state.compare_exchange_weak(
+                    s,
+                    WRITE_LOCKED,
+                    Ordering::Acquire,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => return RwLockWriteGuard { lock: self },
+                    Err(e) => {
+                        s = e;
+                        continue;
+                    }
+                }
+            }
+            // Readers inside, stop new ones coming in. Relaxed, the bit only
+            // affects who gets the lock next, it doesn't protect any data
+            if s & WRITER_WAITING == 0 {
+                match self.state.compare_exchange(
+                    s,
+                    s + WRITER_WAITING,
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => {}
+                    Err(e) => {
+                        s = e;
+                        continue;
+                    }
+                }
+            }
+            backoff.snooze();
+            s = self.state.load(Ordering::Relaxed);
+        }
+    }
+
+    // Succeeds if there are no readers, even if another writer is waiting.
+    // Nobody gets starved by that, the waiting writer is just as happy with
+    // the lock being taken by us as by a reader
+    pub fn try_write(&self) -> Option<RwLockWriteGuard<'_, T>> {
+        let mut s = self.state.load(Ordering::Relaxed);
+        while s <= WRITER_WAITING {
+            match self
+                .state
+                .compare_exchange(s, WRITE_LOCKED, Ordering::Acquire, Ordering::Relaxed)
+            {
+                Ok(_) => return Some(RwLockWriteGuard { lock: self }),
+                Err(e) => s = e,
+            }
+        }
+        None
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.v.get_mut()
+    }
+
+    pub fn into_inner(self) -> T {
+        self.v.into_inner()
+    }
+}
+
+impl<T: Default> Default for RwLock<T> {
+    fn default() -> Self {
+        RwLock::new(T::default())
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for RwLock<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut d = f.debug_struct("RwLock");
+        match self.try_read() {
+            Some(guard) => d.field("data", &&*guard),
+            None => d.field("data", &format_args!("<locked>")),
+        };
+        d.finish_non_exhaustive()
+    }
+}
+
+impl<T> Deref for RwLockReadGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        // SAFETY: while any reader holds the lock there can be no writer, so
+        // nobody has a &mut T
+        unsafe { &*self.lock.v.get_shared() }
+    }
+}
+
+impl<T> Drop for RwLockReadGuard<'_, T> {
+    fn drop(&mut self) {
+        // Release pairs with the Acquire in write, so our reads all happen
+        // before the writer changes anything. Leaves the waiting bit alone
+        self.lock.state.fetch_sub(READER, Ordering::Release);
+    }
+}
+
+impl<T> Deref for RwLockWriteGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        // SAFETY: we are the only one holding the lock
+        unsafe { &*self.lock.v.get() }
+    }
+}
+
+impl<T> DerefMut for RwLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        // SAFETY: we are the only one holding the lock, and &mut self stops
+        // the guard handing out two of these
+        unsafe { &mut *self.lock.v.get() }
+    }
+}
+
+impl<T> Drop for RwLockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        // Release pairs with the Acquire in read and write, so whoever is next
+        // sees our writes. This also clears the waiting bit, any writer still
+        // waiting sets it again next time it looks
+        self.lock.state.store(0, Ordering::Release);
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for RwLockReadGuard<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for RwLockWriteGuard<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+#[cfg(all(test, not(feature = "loom")))]
+mod test {
+    use super::{RwLock, MAX_READ_LOCKED, READER, WRITER_WAITING, WRITE_LOCKED};
+    use std::sync::atomic::Ordering;
+    use std::thread;
+
+    #[test]
+    fn readers_share_writers_dont() {
+        let l = RwLock::new(1);
+        let r1 = l.read();
+        let r2 = l.try_read().unwrap();
+        assert_eq!(*r1 + *r2, 2);
+        assert!(l.try_write().is_none());
+        drop((r1, r2));
+
+        let mut w = l.write();
+        *w += 1;
+        assert!(l.try_read().is_none());
+        assert!(l.try_write().is_none());
+        assert_eq!(format!("{:?}", l), "RwLock { data: <locked>, .. }");
+        drop(w);
+        assert_eq!(format!("{:?}", l), "RwLock { data: 2, .. }");
+        assert_eq!(l.into_inner(), 2);
+    }
+
+    #[test]
+    fn waiting_writer_blocks_new_readers() {
+        let l = RwLock::new(0);
+        thread::scope(|s| {
+            let r = l.read();
+            let w = s.spawn(|| *l.write() += 1);
+            while l.state.load(Ordering::Relaxed) & WRITER_WAITING == 0 {
+                thread::yield_now();
+            }
+            // we could still read, but we step aside for the writer
+            assert!(l.try_read().is_none());
+            assert_eq!(*r, 0);
+            drop(r);
+            w.join().unwrap();
+        });
+        assert_eq!(*l.read(), 1);
+        assert_eq!(l.state.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "too many readers")]
+    fn too_many_readers() {
+        let l = RwLock::new(());
+        // pretend nearly all the readers there is room for are already in
+        l.state.store(MAX_READ_LOCKED - READER, Ordering::Relaxed);
+        let _r = l.read();
+        // the last one in still leaves a waiting writer distinguishable
+        let s = l.state.load(Ordering::Relaxed);
+        assert_ne!(s + WRITER_WAITING, WRITE_LOCKED);
+        let _r2 = l.try_read();
+    }
+
+    #[test]
+    fn no_torn_reads() {
+        // Writers fill the whole array with one value. If a reader could ever
+        // get in halfway through a write it would see two different values
+        let l = RwLock::new([0u64; 16]);
+        thread::scope(|s| {
+            for w in 1..=2u64 {
+                let l = &l;
+                s.spawn(move || {
+                    for i in 0..2000 {
+                        let mut g = l.write();
+                        for x in g.iter_mut() {
+                            *x = w * 1_000_000 + i;
+                        }
+                    }
+                });
+            }
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..5000 {
+                        let g = l.read();
+                        assert!(g.iter().all(|&x| x == g[0]), "torn read: {:?}", *g);
+                    }
+                });
+            }
+        });
+        let last = l.into_inner();
+        assert!(last.iter().all(|&x| x == last[0]));
+        assert!(last[0] % 1_000_000 == 1999);
+    }
+
+    #[test]
+    fn writers_dont_lose_updates() {
+        let mut l = RwLock::new(0);
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..10000 {
+                        *l.write() += 1;
+                        let _ = *l.read();
+                        if let Some(mut g) = l.try_write() {
+                            *g += 1;
+                        }
+                    }
+                });
+            }
+        });
+        assert!(*l.get_mut() >= 4 * 10000);
+        assert_eq!(l.state.load(Ordering::Relaxed), 0);
+    }
+}
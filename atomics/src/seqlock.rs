@@ -0,0 +1,190 @@
+// A lock where readers never write anything, so they never slow the writer
+// down, or each other. Good for small Copy data that is read much more often
+// than it changes, like a snapshot of some stats.
+//
+// Next to the data is a sequence number, even while nobody is writing. The
+// writer makes it odd, writes, and makes it even again. A reader loads the
+// sequence number, copies the data out, and loads it again: if it was even and
+// didn't change, no write overlapped the copy, otherwise the copy may be torn
+// (half old and half new) and the reader throws it away and tries again.
+//
+// The reader only ever reads, so the writer is never kept waiting, but a
+// reader can be starved by a writer that writes all the time. RwLock is the
+// other way around.
+//
+// The data is read while it may be written, so it can't be behind plain
+// loads and stores with Acquire/Release on the sequence number, the orderings
+// have to come from fences (see sync::RacyCell):
+//
+//   writer                          reader
+//   seq = odd                       s1 = seq (Acquire)
+//   fence(Release)                  copy data
+//   write data                      fence(Acquire)
+//   seq = even (Release)            s2 = seq
+//
+// If the copy saw any of the writer's data, the Acquire fence after it pairs
+// with the Release fence before the write, so s2 sees at least the odd value
+// and the reader retries. If s1 saw the final even value, the Acquire load
+// pairs with the Release store, so the copy sees all of the data.
+use crate::spin::Backoff;
+use crate::sync::{fence, maybe_const_fn, AtomicUsize, Ordering, RacyCell};
+use std::fmt;
+
+pub struct SeqLock<T> {
+    seq: AtomicUsize,
+    data: RacyCell<T>,
+}
+
+// Readers on other threads get copies of T, the writer moves one in, so Send
+// is all T needs
+unsafe impl<T: Copy + Send> Sync for SeqLock<T> {}
+
+impl<T: Copy> SeqLock<T> {
+    maybe_const_fn! {
+        pub fn new(t: T) -> Self {
+            SeqLock {
+                seq: AtomicUsize::new(0),
+                data: RacyCell::new(t),
+            }
+        }
+    }
+
+    pub fn read(&self) -> T {
+        let mut backoff = Backoff::new();
+        loop {
+            // Acquire pairs with the Release store that finished the last write
+            let s1 = self.seq.load(Ordering::Acquire);
+            if s1 & 1 == 0 {
+                // SAFETY: the value may be torn, we only keep it if the
+                // sequence number says no write overlapped
+                let t = unsafe { self.data.read() };
+                // keeps the reads above before the load below, and pairs with
+                // the Release fence in write
+                fence(Ordering::Acquire);
+                if self.seq.load(Ordering::Relaxed) == s1 {
+                    // SAFETY: no write overlapped, so t is whole, the T that
+                    // the last write put there
+                    return unsafe { t.assume_init() };
+                }
+            }
+            // a write is going on
+            backoff.snooze();
+        }
+    }
+
+    // Meant for a single writer. If there are more they take turns, each
+    // waits for the one before to finish
+    pub fn write(&self, t: T) {
+        let mut backoff = Backoff::new();
+        let mut s = self.seq.load(Ordering::Relaxed);
+        loop {
+            if s & 1 == 0 {
+                // Acquire so we see the previous writer's data, like any lock
+                match self.seq.compare_exchange_weak(
+                    s,
+                    s.wrapping_add(1),
+                    Ordering::Acquire,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => break,
+                    Err(e) => {
+                        s = e;
+                        continue;
+                    }
+                }
+            }
+            backoff.snooze();
+            s = self.seq.load(Ordering::Relaxed);
+        }
+        // Keeps the odd store above before the data writes. A Release store
+        // wouldn't do, it only keeps things from moving after it, not before
+        fence(Ordering::Release);
+        // SAFETY: the odd sequence number keeps other writers out
+        unsafe { self.data.write(t) };
+        // Release pairs with the Acquire load in read
+        self.seq.store(s.wrapping_add(2), Ordering::Release);
+    }
+
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: Copy + Default> Default for SeqLock<T> {
+    fn default() -> Self {
+        SeqLock::new(T::default())
+    }
+}
+
+impl<T: Copy + fmt::Debug> fmt::Debug for SeqLock<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SeqLock")
+            .field("data", &self.read())
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(all(test, not(feature = "loom")))]
+mod test {
+    use super::SeqLock;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread;
+
+    #[test]
+    fn read_what_was_written() {
+        let l = SeqLock::new((1, 'a'));
+        assert_eq!(l.read(), (1, 'a'));
+        l.write((2, 'b'));
+        assert_eq!(l.read(), (2, 'b'));
+        assert_eq!(format!("{:?}", l), "SeqLock { data: (2, 'b'), .. }");
+        assert_eq!(l.seq.load(Ordering::Relaxed), 2);
+        assert_eq!(l.into_inner(), (2, 'b'));
+    }
+
+    #[test]
+    fn no_torn_reads() {
+        // Same as the RwLock one: the writer fills the whole array with one
+        // value, so a torn copy would have two different values in it
+        let l = SeqLock::new([0u64; 16]);
+        let done = AtomicBool::new(false);
+        thread::scope(|s| {
+            s.spawn(|| {
+                for i in 1..=20000 {
+                    l.write([i; 16]);
+                }
+                done.store(true, Ordering::Relaxed);
+            });
+            for _ in 0..4 {
+                s.spawn(|| {
+                    let mut last = 0;
+                    while !done.load(Ordering::Relaxed) {
+                        let v = l.read();
+                        assert!(v.iter().all(|&x| x == v[0]), "torn read: {:?}", v);
+                        // and the writes are seen in order
+                        assert!(v[0] >= last);
+                        last = v[0];
+                    }
+                });
+            }
+        });
+        assert_eq!(l.read(), [20000; 16]);
+    }
+
+    #[test]
+    fn writers_take_turns() {
+        let l = SeqLock::new((0u64, 0u64));
+        thread::scope(|s| {
+            for w in 1..=4 {
+                let l = &l;
+                s.spawn(move || {
+                    for _ in 0..5000 {
+                        l.write((w, w));
+                        let (a, b) = l.read();
+                        assert_eq!(a, b);
+                    }
+                });
+            }
+        });
+        assert_eq!(l.seq.load(Ordering::Relaxed), 4 * 5000 * 2);
+    }
+}
@@ -0,0 +1,332 @@
+// three::Mutex made usable: same Acquire/Release locking, but with a guard
+// instead of a closure, so the lock can be held across a ? or returned from a
+// function, and with a backoff so waiting threads get out of each other's way
+use super::{LOCKED, UNLOCKED};
+use std::fmt;
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
+use crate::sync::{maybe_const_fn, spin_loop, yield_now, AtomicBool, Ordering, UnsafeCell};
+
+// Each failed attempt spins twice as long as the one before (1, 2, 4 .. 64
+// spin_loop hints). Past that the lock is probably held by a thread that isn't
+// running right now, so spinning just burns our time slice, give it to the OS
+// instead
+pub(crate) struct Backoff {
+    step: u32,
+}
+
+impl Backoff {
+    // Under loom every spin_loop is a point where it tries switching threads,
+    // each of which multiplies the executions it has to go through
+    const SPIN_STEPS: u32 = if cfg!(feature = "loom") { 1 } else { 7 };
+
+    pub(crate) fn new() -> Self {
+        Backoff { step: 0 }
+    }
+
+    pub(crate) fn snooze(&mut self) {
+        if self.step < Self::SPIN_STEPS {
+            for _ in 0..1 << self.step {
+                // tells the CPU we're in a spin loop, on x86 this is PAUSE,
+                // which stops the loop from hammering the cache line and
+                // lets a hyperthread sibling run
+                spin_loop();
+            }
+            self.step += 1;
+        } else {
+            yield_now();
+        }
+    }
+}
+
+pub struct SpinMutex<T> {
+    locked: AtomicBool,
+    // Only checked and set if the mutex was created with poisoning. A plain
+    // SpinMutex behaves like a parking_lot Mutex and ignores panics
+    poisoning: bool,
+    poisoned: AtomicBool,
+    v: UnsafeCell<T>,
+}
+
+// Same as three::Mutex: the lock hands out &mut T to one thread at a time, so
+// T only needs to be Send, not Sync
+unsafe impl<T> Sync for SpinMutex<T> where T: Send {}
+
+// The guard derefs to the value and releases the lock when it is dropped
+pub struct SpinMutexGuard<'a, T> {
+    lock: &'a SpinMutex<T>,
+    // whether this thread was already panicking when it took the lock, so
+    // taking a lock in a Drop impl during unwinding doesn't poison it
+    panicking: bool,
+    // &SpinMutex<T> is Sync whenever T is Send, but a shared guard hands out
+    // &T to every thread it is shared with, so that needs T: Sync. The guard
+    // behaves like a &mut T, so borrow its Send/Sync. Unlike a pthread mutex a
+    // spinlock doesn't care which thread unlocks it, so Send is fine
+    _marker: PhantomData<&'a mut T>,
+}
+
+impl<T> SpinMutex<T> {
+    maybe_const_fn! {
+        pub fn new(t: T) -> Self {
+            Self {
+                locked: AtomicBool::new(UNLOCKED),
+                poisoning: false,
+                poisoned: AtomicBool::new(false),
+                v: UnsafeCell::new(t),
+            }
+        }
+    }
+
+    // A thread panicking while holding the guard poisons the mutex, after that
+    // lock, try_lock, get_mut and into_inner panic, the same as calling
+    // .lock().unwrap() on a std Mutex. Use this when a panic halfway through
+    // the critical section could leave T broken
+    maybe_const_fn! {
+        pub fn with_poisoning(t: T) -> Self {
+            let mut m = Self::new(t);
+            m.poisoning = true;
+            m
+        }
+    }
+
+    #[track_caller]
+    pub fn lock(&self) -> SpinMutexGuard<'_, T> {
+        let mut backoff = Backoff::new();
+        // Acquire on success pairs with the Release in the guard's drop, see
+        // three::Mutex. Failing to take the lock doesn't give us access to
+        // anything so it can be Relaxed
+        while self
+            .locked
+            .compare_exchange_weak(UNLOCKED, LOCKED, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            // stay in the S state of MESI until it looks unlocked
+            while self.locked.load(Ordering::Relaxed) == LOCKED {
+                backoff.snooze();
+            }
+        }
+        self.guard()
+    }
+
+    // Never waits, None if someone else has the lock
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<SpinMutexGuard<'_, T>> {
+        // not _weak, a spurious failure would look like the lock is taken
+        self.locked
+            .compare_exchange(UNLOCKED, LOCKED, Ordering::Acquire, Ordering::Relaxed)
+            .ok()
+            .map(|_| self.guard())
+    }
+
+    // &mut self, so nobody can be holding the lock
+    #[track_caller]
+    pub fn get_mut(&mut self) -> &mut T {
+        self.check_poison();
+        self.v.get_mut()
+    }
+
+    #[track_caller]
+    pub fn into_inner(self) -> T {
+        self.check_poison();
+        self.v.into_inner()
+    }
+
+    pub fn is_poisoned(&self) -> bool {
+        self.poisoned.load(Ordering::Relaxed)
+    }
+
+    // For when whoever catches the panic has put T back into a good state
+    pub fn clear_poison(&self) {
+        self.poisoned.store(false, Ordering::Relaxed);
+    }
+
+    // Only call with the lock held
+    #[track_caller]
+    fn guard(&self) -> SpinMutexGuard<'_, T> {
+        // build the guard first, so the lock is released again if we panic
+        let guard = self.guard_unpoisoned();
+        self.check_poison();
+        guard
+    }
+
+    fn guard_unpoisoned(&self) -> SpinMutexGuard<'_, T> {
+        SpinMutexGuard {
+            lock: self,
+            panicking: std::thread::panicking(),
+            _marker: PhantomData,
+        }
+    }
+
+    #[track_caller]
+    fn check_poison(&self) {
+        if self.is_poisoned() {
+            panic!("SpinMutex poisoned: a thread panicked while holding the lock");
+        }
+    }
+}
+
+impl<T: Default> Default for SpinMutex<T> {
+    fn default() -> Self {
+        SpinMutex::new(T::default())
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for SpinMutex<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut d = f.debug_struct("SpinMutex");
+        // don't wait for the lock, printing could be happening while it is held
+        // by this very thread. And don't panic if it is poisoned
+        let guard = self
+            .locked
+            .compare_exchange(UNLOCKED, LOCKED, Ordering::Acquire, Ordering::Relaxed)
+            .ok()
+            .map(|_| self.guard_unpoisoned());
+        match guard {
+            Some(guard) => d.field("data", &&*guard),
+            None => d.field("data", &format_args!("<locked>")),
+        };
+        d.field("poisoned", &self.is_poisoned());
+        d.finish_non_exhaustive()
+    }
+}
+
+impl<T> Deref for SpinMutexGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        // SAFETY: we hold the lock, so nobody else has a reference to v
+        unsafe { &*self.lock.v.get() }
+    }
+}
+
+impl<T> DerefMut for SpinMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        // SAFETY: we hold the lock, and &mut self means this is the only
+        // reference handed out through the guard
+        unsafe { &mut *self.lock.v.get() }
+    }
+}
+
+impl<T> Drop for SpinMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        if self.lock.poisoning && !self.panicking && std::thread::panicking() {
+            self.lock.poisoned.store(true, Ordering::Relaxed);
+        }
+        // Release, so the next thread to Acquire the lock sees everything we
+        // did to v (and the poison flag) while we held it
+        self.lock.locked.store(UNLOCKED, Ordering::Release);
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for SpinMutexGuard<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+#[cfg(all(test, not(feature = "loom")))]
+mod test {
+    use super::SpinMutex;
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+    use std::thread::spawn;
+
+    #[test]
+    fn guard_and_try_lock() {
+        let m = SpinMutex::new(vec![1]);
+        let mut g = m.lock();
+        g.push(2);
+        assert!(m.try_lock().is_none());
+        drop(g);
+        assert_eq!(*m.try_lock().unwrap(), [1, 2]);
+        assert_eq!(m.into_inner(), [1, 2]);
+    }
+
+    #[test]
+    fn get_mut() {
+        let mut m = SpinMutex::new(0);
+        *m.get_mut() += 1;
+        assert_eq!(*m.lock(), 1);
+    }
+
+    #[test]
+    fn debug() {
+        let m = SpinMutex::new(1);
+        assert_eq!(
+            format!("{:?}", m),
+            "SpinMutex { data: 1, poisoned: false, .. }"
+        );
+        let g = m.lock();
+        assert_eq!(
+            format!("{:?}", m),
+            "SpinMutex { data: <locked>, poisoned: false, .. }"
+        );
+        assert_eq!(format!("{:?}", g), "1");
+    }
+
+    #[test]
+    fn not_poisoned_by_default() {
+        let m = SpinMutex::new(0);
+        let r = catch_unwind(AssertUnwindSafe(|| {
+            let mut g = m.lock();
+            *g += 1;
+            panic!();
+        }));
+        assert!(r.is_err());
+        assert!(!m.is_poisoned());
+        assert_eq!(*m.lock(), 1);
+    }
+
+    #[test]
+    fn poisoning() {
+        let m = SpinMutex::with_poisoning(0);
+        let r = catch_unwind(AssertUnwindSafe(|| {
+            let _g = m.lock();
+            panic!();
+        }));
+        assert!(r.is_err());
+        assert!(m.is_poisoned());
+        // the lock itself was still released
+        assert!(!m.locked.load(std::sync::atomic::Ordering::Relaxed));
+
+        let r = catch_unwind(AssertUnwindSafe(|| *m.lock()));
+        let msg = r.unwrap_err();
+        assert!(msg
+            .downcast_ref::<&str>()
+            .unwrap()
+            .starts_with("SpinMutex poisoned"));
+        assert!(!m.locked.load(std::sync::atomic::Ordering::Relaxed));
+
+        m.clear_poison();
+        assert_eq!(m.into_inner(), 0);
+    }
+
+    #[test]
+    fn lock_while_unwinding_does_not_poison() {
+        struct LockOnDrop<'a>(&'a SpinMutex<i32>);
+        impl Drop for LockOnDrop<'_> {
+            fn drop(&mut self) {
+                *self.0.lock() += 1;
+            }
+        }
+
+        let m = SpinMutex::with_poisoning(0);
+        let r = catch_unwind(AssertUnwindSafe(|| {
+            let _d = LockOnDrop(&m);
+            panic!();
+        }));
+        assert!(r.is_err());
+        assert!(!m.is_poisoned());
+        assert_eq!(*m.lock(), 1);
+    }
+
+    #[test]
+    fn contended() {
+        let m: &'static _ = Box::leak(Box::new(SpinMutex::new(Vec::new())));
+        let handles: Vec<_> = (0..4).map(|i| spawn(move || m.lock().push(i))).collect();
+        for h in handles {
+            h.join().unwrap();
+        }
+        let mut v = m.lock().clone();
+        v.sort();
+        assert_eq!(v, [0, 1, 2, 3]);
+    }
+}
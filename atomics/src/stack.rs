@@ -0,0 +1,200 @@
+// A lock-free stack: a linked list where push and pop both just swing head
+// with compare_exchange. If another thread changed head in between our load
+// and our exchange, the exchange fails and we go round again with the new
+// head. Some thread always succeeds, so the stack as a whole makes progress
+// even if one thread is descheduled halfway through, which a lock can't promise
+//
+// Popped nodes go to the epoch Collector instead of being freed on the spot,
+// another pop may have loaded the same head and be about to read its next
+use crate::epoch::Collector;
+use crate::sync::{AtomicPtr, Ordering};
+use std::mem::ManuallyDrop;
+use std::ptr;
+
+pub struct TreiberStack<T> {
+    head: AtomicPtr<Node<T>>,
+    collector: Collector,
+}
+
+struct Node<T> {
+    // moved out by pop, the node itself is freed later by the collector,
+    // which must not drop the value a second time
+    value: ManuallyDrop<T>,
+    next: *mut Node<T>,
+}
+
+// The values move between threads, but are never shared, so Send is enough
+unsafe impl<T: Send> Send for TreiberStack<T> {}
+unsafe impl<T: Send> Sync for TreiberStack<T> {}
+
+impl<T> TreiberStack<T> {
+    pub fn new() -> Self {
+        TreiberStack {
+            head: AtomicPtr::new(ptr::null_mut()),
+            collector: Collector::new(),
+        }
+    }
+
+    pub fn push(&self, value: T) {
+        let node = Box::into_raw(Box::new(Node {
+            value: ManuallyDrop::new(value),
+            next: ptr::null_mut(),
+        }));
+        // No need to pin, we never look inside the nodes already on the stack
+        let mut head = self.head.load(Ordering::Relaxed);
+        loop {
+            // SAFETY: the node isn't shared until the exchange succeeds
+            unsafe { (*node).next = head };
+            // Release so a pop that Acquires the new head sees value and next
+            // _weak since we loop anyway, see two::Mutex
+            match self
+                .head
+                .compare_exchange_weak(head, node, Ordering::Release, Ordering::Relaxed)
+            {
+                Ok(_) => return,
+                Err(h) => head = h,
+            }
+        }
+    }
+
+    pub fn pop(&self) -> Option<T> {
+        let guard = self.collector.pin();
+        // Acquire pairs with the Release in push, so the node is initialised
+        let mut head = self.head.load(Ordering::Acquire);
+        loop {
+            if head.is_null() {
+                return None;
+            }
+            // SAFETY: we are pinned, so even if another pop has unlinked head
+            // since we loaded it, it hasn't been freed
+            let next = unsafe { (*head).next };
+            // If head is still what we loaded, nobody popped it. Without the
+            // collector the node could have been popped, freed, and a new
+            // node allocated at the same address and pushed (ABA), making
+            // the exchange succeed with a stale next
+            match self
+                .head
+                .compare_exchange_weak(head, next, Ordering::Acquire, Ordering::Acquire)
+            {
+                Ok(_) => {
+                    // SAFETY: the exchange unlinked the node, so we are the only
+                    // ones who will take the value out of it. Other threads
+                    // may still read next, so the node goes to the collector
+                    unsafe {
+                        let value = ManuallyDrop::into_inner(ptr::read(&(*head).value));
+                        guard.defer_destroy(head);
+                        return Some(value);
+                    }
+                }
+                Err(h) => head = h,
+            }
+        }
+    }
+
+    // Only a snapshot, another thread may push or pop right after
+    pub fn is_empty(&self) -> bool {
+        self.head.load(Ordering::Relaxed).is_null()
+    }
+}
+
+impl<T> Default for TreiberStack<T> {
+    fn default() -> Self {
+        TreiberStack::new()
+    }
+}
+
+impl<T> Drop for TreiberStack<T> {
+    fn drop(&mut self) {
+        // &mut self, no other thread can be using the stack, so the nodes
+        // still on it can be freed right away
+        let mut p = self.head.load(Ordering::Relaxed);
+        while !p.is_null() {
+            // SAFETY: every node came from Box::into_raw in push, and the ones
+            // still linked haven't been given to the collector
+            let mut node = unsafe { Box::from_raw(p) };
+            unsafe { ManuallyDrop::drop(&mut node.value) };
+            p = node.next;
+        }
+        // the collector then frees the popped nodes
+    }
+}
+
+#[cfg(all(test, not(feature = "loom")))]
+mod test {
+    use super::TreiberStack;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+
+    #[test]
+    fn lifo() {
+        let s = TreiberStack::new();
+        assert!(s.is_empty());
+        s.push(1);
+        s.push(2);
+        s.push(3);
+        assert_eq!(s.pop(), Some(3));
+        s.push(4);
+        assert_eq!(s.pop(), Some(4));
+        assert_eq!(s.pop(), Some(2));
+        assert_eq!(s.pop(), Some(1));
+        assert_eq!(s.pop(), None);
+        assert!(s.is_empty());
+    }
+
+    #[test]
+    fn drops_every_value_once() {
+        struct D<'a>(&'a AtomicUsize);
+        impl Drop for D<'_> {
+            fn drop(&mut self) {
+                self.0.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        let drops = AtomicUsize::new(0);
+        let s = TreiberStack::new();
+        for _ in 0..10 {
+            s.push(D(&drops));
+        }
+        for _ in 0..4 {
+            drop(s.pop());
+        }
+        assert_eq!(drops.load(Ordering::Relaxed), 4);
+        drop(s);
+        assert_eq!(drops.load(Ordering::Relaxed), 10);
+    }
+
+    #[test]
+    fn stress() {
+        const THREADS: usize = 4;
+        const N: usize = 20000;
+        let s = TreiberStack::new();
+        let popped: Vec<Vec<usize>> = thread::scope(|sc| {
+            let handles: Vec<_> = (0..THREADS)
+                .map(|t| {
+                    let s = &s;
+                    sc.spawn(move || {
+                        let mut popped = Vec::new();
+                        for i in 0..N {
+                            s.push(t * N + i);
+                            // pop roughly every other time, so the stack
+                            // keeps going from empty to not empty
+                            if i % 2 == 0 {
+                                popped.extend(s.pop());
+                            }
+                        }
+                        popped
+                    })
+                })
+                .collect();
+            handles.into_iter().map(|h| h.join().unwrap()).collect()
+        });
+
+        let mut all: Vec<usize> = popped.into_iter().flatten().collect();
+        while let Some(v) = s.pop() {
+            all.push(v);
+        }
+        all.sort();
+        // every value came out exactly once
+        assert_eq!(all, (0..THREADS * N).collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,206 @@
+// Everything in the crate gets its atomics, UnsafeCell and thread functions
+// from here instead of from std. With the loom feature they are loom's, which
+// run a test under every interleaving of its threads, and every value each
+// load is allowed to see, instead of the one the OS and CPU happened to pick.
+// See the models module.
+//
+//   cargo test --release --features loom models
+//
+// loom's types only work inside loom::model, so the normal tests are turned
+// off with the feature on, and the futex Mutex (which goes to the kernel, where
+// loom can't follow) isn't built at all
+#[cfg(feature = "loom")]
+pub(crate) use loom::{
+    hint::spin_loop,
+    sync::atomic::{fence, AtomicBool, AtomicPtr, AtomicU32, AtomicU64, AtomicUsize},
+    thread::yield_now,
+};
+#[cfg(not(feature = "loom"))]
+pub(crate) use std::{
+    hint::spin_loop,
+    sync::atomic::{fence, AtomicBool, AtomicPtr, AtomicU32, AtomicU64, AtomicUsize},
+    thread::yield_now,
+};
+// loom uses std's
+pub(crate) use std::sync::atomic::Ordering;
+
+use std::mem::MaybeUninit;
+
+// loom's UnsafeCell records every access to check that none of them race. Its
+// API is closure based (with/with_mut), so both versions are wrapped up to
+// look like std's. The access is recorded when get is called, which for all
+// the locks here is right where the reference is made.
+//
+// get is a write access, get_shared a read access. loom complains about any
+// two writes (or a read and a write) that don't happen-before one another, but
+// reads may overlap, which RwLock relies on
+#[cfg(not(feature = "loom"))]
+#[derive(Debug)]
+pub(crate) struct UnsafeCell<T>(std::cell::UnsafeCell<T>);
+
+#[cfg(not(feature = "loom"))]
+impl<T> UnsafeCell<T> {
+    pub(crate) const fn new(t: T) -> Self {
+        UnsafeCell(std::cell::UnsafeCell::new(t))
+    }
+
+    pub(crate) fn get(&self) -> *mut T {
+        self.0.get()
+    }
+
+    pub(crate) fn get_shared(&self) -> *const T {
+        self.0.get()
+    }
+
+    pub(crate) fn get_mut(&mut self) -> &mut T {
+        self.0.get_mut()
+    }
+
+    pub(crate) fn into_inner(self) -> T {
+        self.0.into_inner()
+    }
+}
+
+#[cfg(feature = "loom")]
+#[derive(Debug)]
+pub(crate) struct UnsafeCell<T>(loom::cell::UnsafeCell<T>);
+
+#[cfg(feature = "loom")]
+impl<T> UnsafeCell<T> {
+    pub(crate) fn new(t: T) -> Self {
+        UnsafeCell(loom::cell::UnsafeCell::new(t))
+    }
+
+    pub(crate) fn get(&self) -> *mut T {
+        self.0.with_mut(|p| p)
+    }
+
+    pub(crate) fn get_shared(&self) -> *const T {
+        self.0.with(|p| p)
+    }
+
+    pub(crate) fn get_mut(&mut self) -> &mut T {
+        // SAFETY: &mut self, nobody else can be using it
+        unsafe { &mut *self.0.with_mut(|p| p) }
+    }
+
+    pub(crate) fn into_inner(self) -> T {
+        self.0.into_inner()
+    }
+}
+
+// For data that is read while it may be being written, the way SeqLock reads
+// optimistically and throws the value away if a writer got in. Rust has no
+// blessed way to do that (the atomic memcpy RFC would be it), so like
+// crossbeam we use volatile reads and writes: the compiler has to do them as
+// written and can't assume the value is stable. The fences around them are
+// what actually order them with the sequence number.
+//
+// A torn value is half of one T and half of another, which for a T with
+// invalid bit patterns (bool, char, enums, references) may not be a valid T at
+// all. Even making one is UB, whether or not it is used afterwards. So read
+// hands out a MaybeUninit<T>, and the caller only calls assume_init once it
+// knows the read wasn't torn.
+//
+// Under loom a racy read of an UnsafeCell is always a causality violation,
+// even if the value is thrown away. So there the data is kept in Relaxed
+// atomic words instead, which loom allows to race, and which it lets see any
+// old value the fences don't rule out. That is exactly what the fences are
+// for, and what the seqlock models check
+#[cfg(not(feature = "loom"))]
+pub(crate) struct RacyCell<T>(std::cell::UnsafeCell<T>);
+
+#[cfg(not(feature = "loom"))]
+impl<T: Copy> RacyCell<T> {
+    pub(crate) const fn new(t: T) -> Self {
+        RacyCell(std::cell::UnsafeCell::new(t))
+    }
+
+    // SAFETY: the value may be torn if a write is going on at the same time,
+    // the caller has to find out (and not assume_init it) some other way
+    pub(crate) unsafe fn read(&self) -> MaybeUninit<T> {
+        std::ptr::read_volatile(self.0.get() as *const MaybeUninit<T>)
+    }
+
+    // SAFETY: there must be no other write at the same time
+    pub(crate) unsafe fn write(&self, t: T) {
+        std::ptr::write_volatile(self.0.get(), t)
+    }
+
+    pub(crate) fn into_inner(self) -> T {
+        self.0.into_inner()
+    }
+}
+
+#[cfg(feature = "loom")]
+pub(crate) struct RacyCell<T> {
+    words: Box<[AtomicUsize]>,
+    _marker: std::marker::PhantomData<T>,
+}
+
+#[cfg(feature = "loom")]
+impl<T: Copy> RacyCell<T> {
+    const WORD: usize = std::mem::size_of::<usize>();
+
+    pub(crate) fn new(t: T) -> Self {
+        // only for the models, their T is always a few usizes
+        assert!(
+            std::mem::size_of::<T>().is_multiple_of(Self::WORD),
+            "under loom RacyCell only holds whole words"
+        );
+        let words = (0..std::mem::size_of::<T>() / Self::WORD)
+            .map(|_| AtomicUsize::new(0))
+            .collect();
+        let cell = RacyCell {
+            words,
+            _marker: std::marker::PhantomData,
+        };
+        // SAFETY: nobody else has it yet
+        unsafe { cell.write(t) };
+        cell
+    }
+
+    pub(crate) unsafe fn read(&self) -> MaybeUninit<T> {
+        let mut t = MaybeUninit::<T>::uninit();
+        for (i, w) in self.words.iter().enumerate() {
+            let v = w.load(Ordering::Relaxed);
+            std::ptr::copy_nonoverlapping(
+                &v as *const usize as *const u8,
+                (t.as_mut_ptr() as *mut u8).add(i * Self::WORD),
+                Self::WORD,
+            );
+        }
+        t
+    }
+
+    pub(crate) unsafe fn write(&self, t: T) {
+        let mut words = vec![0usize; self.words.len()];
+        std::ptr::copy_nonoverlapping(
+            &t as *const T as *const u8,
+            words.as_mut_ptr() as *mut u8,
+            std::mem::size_of::<T>(),
+        );
+        for (w, v) in self.words.iter().zip(words) {
+            w.store(v, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn into_inner(self) -> T {
+        // SAFETY: we own it, no writes going on, so the read isn't torn
+        unsafe { self.read().assume_init() }
+    }
+}
+
+// loom's atomics and cells can't be created in a const fn, so constructors are
+// only const without loom:
+//
+//   maybe_const_fn! { pub fn new(t: T) -> Self { .. } }
+macro_rules! maybe_const_fn {
+    ($(#[$attr:meta])* $vis:vis fn $($rest:tt)*) => {
+        #[cfg(not(feature = "loom"))]
+        $(#[$attr])* $vis const fn $($rest)*
+        #[cfg(feature = "loom")]
+        $(#[$attr])* $vis fn $($rest)*
+    };
+}
+pub(crate) use maybe_const_fn;
@@ -0,0 +1,201 @@
+// A fair spinlock. three::Mutex and SpinMutex let whoever gets to the cache
+// line first have the lock, and the thread that just unlocked usually still
+// has the line, so under contention the same thread can take the lock again
+// and again while the others starve.
+//
+// The ticket lock works like the queue at a deli counter: take a number
+// (fetch_add on next), wait until it is called (serving), and when done call
+// the next number. Threads get the lock in the order they took their tickets.
+//
+// The catch is that it is strictly in order. If the thread with the next
+// ticket isn't running, everyone behind it waits too, even though the lock is
+// free. And all the waiters spin on the same serving counter, so every unlock
+// sends its cache line to every waiting core, see McsLock for that
+use crate::spin::Backoff;
+use crate::sync::{maybe_const_fn, AtomicU32, Ordering, UnsafeCell};
+use std::fmt;
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
+
+pub struct TicketLock<T> {
+    // the ticket the next thread to come along gets
+    next: AtomicU32,
+    // the ticket that holds the lock, or is allowed to take it
+    serving: AtomicU32,
+    v: UnsafeCell<T>,
+}
+
+// Same as SpinMutex
+unsafe impl<T> Sync for TicketLock<T> where T: Send {}
+
+pub struct TicketLockGuard<'a, T> {
+    lock: &'a TicketLock<T>,
+    // see SpinMutexGuard
+    _marker: PhantomData<&'a mut T>,
+}
+
+impl<T> TicketLock<T> {
+    maybe_const_fn! {
+        pub fn new(t: T) -> Self {
+            Self {
+                next: AtomicU32::new(0),
+                serving: AtomicU32::new(0),
+                v: UnsafeCell::new(t),
+            }
+        }
+    }
+
+    pub fn lock(&self) -> TicketLockGuard<'_, T> {
+        // Relaxed, the ticket only decides the order, serving is what hands
+        // over the lock. Wraps around after 4 billion, which is fine as long
+        // as there are fewer waiters than that
+        let ticket = self.next.fetch_add(1, Ordering::Relaxed);
+        let mut backoff = Backoff::new();
+        // Acquire pairs with the Release in the guard's drop
+        while self.serving.load(Ordering::Acquire) != ticket {
+            backoff.snooze();
+        }
+        TicketLockGuard {
+            lock: self,
+            _marker: PhantomData,
+        }
+    }
+
+    // Only takes the lock if nobody holds it or is waiting for it, there is
+    // no way to take a ticket and then give it back
+    pub fn try_lock(&self) -> Option<TicketLockGuard<'_, T>> {
+        // Acquire pairs with the Release in the guard's drop, like lock
+        let serving = self.serving.load(Ordering::Acquire);
+        // If next is still the ticket being served, it is ours
+        self.next
+            .compare_exchange(
+                serving,
+                serving.wrapping_add(1),
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            )
+            .ok()
+            .map(|_| TicketLockGuard {
+                lock: self,
+                _marker: PhantomData,
+            })
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.v.get_mut()
+    }
+
+    pub fn into_inner(self) -> T {
+        self.v.into_inner()
+    }
+}
+
+impl<T: Default> Default for TicketLock<T> {
+    fn default() -> Self {
+        TicketLock::new(T::default())
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for TicketLock<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut d = f.debug_struct("TicketLock");
+        match self.try_lock() {
+            Some(guard) => d.field("data", &&*guard),
+            None => d.field("data", &format_args!("<locked>")),
+        };
+        d.finish_non_exhaustive()
+    }
+}
+
+impl<T> Deref for TicketLockGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        // SAFETY: we hold the lock
+        unsafe { &*self.lock.v.get() }
+    }
+}
+
+impl<T> DerefMut for TicketLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        // SAFETY: we hold the lock, and &mut self stops the guard handing out
+        // two of these
+        unsafe { &mut *self.lock.v.get() }
+    }
+}
+
+impl<T> Drop for TicketLockGuard<'_, T> {
+    fn drop(&mut self) {
+        // Only the holder changes serving, so no need for a read-modify-write.
+        // Release so the next ticket sees what we did to v
+        let serving = self.lock.serving.load(Ordering::Relaxed);
+        self.lock
+            .serving
+            .store(serving.wrapping_add(1), Ordering::Release);
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for TicketLockGuard<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+#[cfg(all(test, not(feature = "loom")))]
+mod test {
+    use super::TicketLock;
+    use std::sync::atomic::Ordering;
+    use std::thread;
+
+    #[test]
+    fn guard_and_try_lock() {
+        let l = TicketLock::new(vec![1]);
+        let mut g = l.lock();
+        g.push(2);
+        assert!(l.try_lock().is_none());
+        assert_eq!(format!("{:?}", l), "TicketLock { data: <locked>, .. }");
+        drop(g);
+        assert_eq!(*l.try_lock().unwrap(), [1, 2]);
+        assert_eq!(format!("{:?}", l), "TicketLock { data: [1, 2], .. }");
+        assert_eq!(l.into_inner(), [1, 2]);
+    }
+
+    #[test]
+    fn served_in_ticket_order() {
+        let l = TicketLock::new(Vec::new());
+        thread::scope(|s| {
+            let g = l.lock();
+            for i in 0..3 {
+                let l = &l;
+                s.spawn(move || l.lock().push(i));
+                // wait for it to take its ticket before starting the next
+                while l.next.load(Ordering::Relaxed) != i + 2 {
+                    thread::yield_now();
+                }
+            }
+            drop(g);
+        });
+        assert_eq!(l.into_inner(), [0, 1, 2]);
+    }
+
+    #[test]
+    fn stress() {
+        let mut l = TicketLock::new(0);
+        thread::scope(|s| {
+            for _ in 0..8 {
+                s.spawn(|| {
+                    for _ in 0..10000 {
+                        *l.lock() += 1;
+                        if let Some(mut g) = l.try_lock() {
+                            *g += 1;
+                        }
+                    }
+                });
+            }
+        });
+        assert!(*l.get_mut() >= 8 * 10000);
+        assert_eq!(
+            l.next.load(Ordering::Relaxed),
+            l.serving.load(Ordering::Relaxed)
+        );
+    }
+}
@@ -1,7 +1,7 @@
 use std::sync::{Arc, Mutex, Condvar};
 use std::collections::VecDeque;
 
-mod one {
+pub mod one {
     use super::*;
 
     struct Inner<T> {
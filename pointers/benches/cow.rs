@@ -0,0 +1,100 @@
+// Compares the Cow returning escape/from_utf8_lossy against versions that
+// always return a String, on mostly clean input. Counts allocations with a
+// wrapper around the system allocator, and times both.
+//
+// cargo bench --bench cow
+use pointers::cow::{escape, from_utf8_lossy};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::hint::black_box;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+struct Counting;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+static BYTES: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        BYTES.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+// What we'd write without Cow: always build a new String
+fn escape_string(s: &str) -> String {
+    escape(s).into_owned()
+}
+
+fn lossy_string(bytes: &[u8]) -> String {
+    from_utf8_lossy(bytes).into_owned()
+}
+
+fn measure<F: FnMut()>(name: &str, mut f: F) {
+    let allocs = ALLOCATIONS.load(Ordering::Relaxed);
+    let bytes = BYTES.load(Ordering::Relaxed);
+    let start = Instant::now();
+    f();
+    let elapsed = start.elapsed();
+    println!(
+        "{:<28} {:>8} allocations {:>10} bytes {:>10.2?}",
+        name,
+        ALLOCATIONS.load(Ordering::Relaxed) - allocs,
+        BYTES.load(Ordering::Relaxed) - bytes,
+        elapsed,
+    );
+}
+
+fn main() {
+    // 1 in 100 strings needs escaping / is not valid utf-8
+    let strings: Vec<String> = (0..100_000)
+        .map(|i| {
+            if i % 100 == 0 {
+                format!("field \"{}\"\n", i)
+            } else {
+                format!("field_{}", i)
+            }
+        })
+        .collect();
+    let bytes: Vec<Vec<u8>> = strings
+        .iter()
+        .enumerate()
+        .map(|(i, s)| {
+            let mut b = s.clone().into_bytes();
+            if i % 100 == 0 {
+                b.push(0xFF);
+            }
+            b
+        })
+        .collect();
+
+    println!("{} inputs, 1% need changing", strings.len());
+    measure("escape -> Cow", || {
+        for s in &strings {
+            black_box(escape(black_box(s)));
+        }
+    });
+    measure("escape -> String", || {
+        for s in &strings {
+            black_box(escape_string(black_box(s)));
+        }
+    });
+    measure("from_utf8_lossy -> Cow", || {
+        for b in &bytes {
+            black_box(from_utf8_lossy(black_box(b)));
+        }
+    });
+    measure("from_utf8_lossy -> String", || {
+        for b in &bytes {
+            black_box(lossy_string(black_box(b)));
+        }
+    });
+}
@@ -0,0 +1,430 @@
+// Arc is Rc with atomic reference counts, so it can be shared across threads
+// Like Rc it does not provide mutability, so to mutate through it you still
+// need something that is Sync, like a Mutex
+use std::ptr::{self, NonNull};
+use std::marker::PhantomData;
+use std::mem::ManuallyDrop;
+// With the loom feature the atomics are swapped for loom's, which explores
+// every interleaving (and every weak memory behaviour) of a test, see the
+// soundness module. The normal tests below don't run under loom
+#[cfg(feature = "loom")]
+use loom::hint::spin_loop;
+#[cfg(feature = "loom")]
+use loom::sync::atomic::{fence, AtomicUsize, Ordering};
+#[cfg(not(feature = "loom"))]
+use std::hint::spin_loop;
+#[cfg(not(feature = "loom"))]
+use std::sync::atomic::{fence, AtomicUsize, Ordering};
+
+// If the count ever gets this high, someone is leaking Arc's with mem::forget
+// in a loop. Going past it could wrap the count around to 0 and free the value
+// while it is still in use, so abort instead. Halfway to usize::MAX leaves
+// plenty of room for the other threads that incremented before they noticed
+const MAX_REFCOUNT: usize = isize::MAX as usize;
+
+// Used as the weak count while get_mut/make_mut check if the Arc is unique
+const LOCKED: usize = usize::MAX;
+
+struct ArcInner<T> {
+    strong: AtomicUsize,
+    // Weak's plus one held collectively by all the Arc's, same as Rc
+    weak: AtomicUsize,
+    value: T,
+}
+
+pub struct Arc<T> {
+    inner: NonNull<ArcInner<T>>,
+    _marker: PhantomData<ArcInner<T>>,
+}
+
+pub struct Weak<T> {
+    inner: NonNull<ArcInner<T>>,
+}
+
+// NonNull is neither Send nor Sync, so we have to tell the compiler.
+// Sending an Arc to another thread lets that thread get a &T (so T: Sync)
+// and possibly be the one that drops the T (so T: Send)
+unsafe impl<T: Send + Sync> Send for Arc<T> {}
+unsafe impl<T: Send + Sync> Sync for Arc<T> {}
+unsafe impl<T: Send + Sync> Send for Weak<T> {}
+unsafe impl<T: Send + Sync> Sync for Weak<T> {}
+
+impl<T> Arc<T> {
+    pub fn new(v: T) -> Self {
+        let inner = Box::new(ArcInner {
+            strong: AtomicUsize::new(1),
+            weak: AtomicUsize::new(1),
+            value: v,
+        });
+        Arc {
+            // SAFETY: Box does not give us a null pointer
+            inner: unsafe { NonNull::new_unchecked(Box::into_raw(inner)) },
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn downgrade(this: &Self) -> Weak<T> {
+        let inner = this.inner();
+        let mut w = inner.weak.load(Ordering::Relaxed);
+        loop {
+            // get_mut is checking whether we are unique, wait for it
+            if w == LOCKED {
+                spin_loop();
+                w = inner.weak.load(Ordering::Relaxed);
+                continue;
+            }
+            if w > MAX_REFCOUNT {
+                std::process::abort();
+            }
+            // Acquire pairs with the Release in is_unique, so we see whatever
+            // the thread that held the lock did
+            match inner.weak.compare_exchange_weak(w, w + 1, Ordering::Acquire, Ordering::Relaxed) {
+                Ok(_) => return Weak { inner: this.inner },
+                Err(actual) => w = actual,
+            }
+        }
+    }
+
+    // The counts may have changed by the time the caller looks at them,
+    // so they are only good for debugging and tests
+    pub fn strong_count(this: &Self) -> usize {
+        this.inner().strong.load(Ordering::Relaxed)
+    }
+
+    pub fn weak_count(this: &Self) -> usize {
+        let w = this.inner().weak.load(Ordering::Relaxed);
+        // if it is LOCKED, the only other Arc is checking it is unique,
+        // so there are no Weak's
+        if w == LOCKED {
+            0
+        } else {
+            w - 1
+        }
+    }
+
+    pub fn ptr_eq(this: &Self, other: &Self) -> bool {
+        this.inner == other.inner
+    }
+
+    pub fn get_mut(this: &mut Self) -> Option<&mut T> {
+        if this.is_unique() {
+            // SAFETY: no other Arc or Weak exists, and we have &mut self
+            Some(unsafe { &mut (*this.inner.as_ptr()).value })
+        } else {
+            None
+        }
+    }
+
+    pub fn try_unwrap(this: Self) -> Result<T, Self> {
+        // Relaxed is enough to claim the value, the fence below is what
+        // synchronizes with the other Arc's being dropped
+        if this
+            .inner()
+            .strong
+            .compare_exchange(1, 0, Ordering::Relaxed, Ordering::Relaxed)
+            .is_err()
+        {
+            return Err(this);
+        }
+        fence(Ordering::Acquire);
+        let this = ManuallyDrop::new(this);
+        // SAFETY: strong is 0 so no Weak can upgrade and see the value
+        let value = unsafe { ptr::read(&this.inner().value) };
+        drop(Weak { inner: this.inner });
+        Ok(value)
+    }
+
+    pub fn into_inner(this: Self) -> Option<T> {
+        // Don't use try_unwrap here: if two threads call into_inner on the
+        // last two Arc's at the same time, both could see strong == 2 and
+        // fail, then drop the Arc's, and the value is lost. Going through
+        // Drop's fetch_sub guarantees exactly one of them gets it
+        let this = ManuallyDrop::new(this);
+        if this.inner().strong.fetch_sub(1, Ordering::Release) != 1 {
+            return None;
+        }
+        fence(Ordering::Acquire);
+        // SAFETY: we were the last Arc
+        let value = unsafe { ptr::read(&this.inner().value) };
+        drop(Weak { inner: this.inner });
+        Some(value)
+    }
+
+    // Unique means one Arc and no Weak's. Checking the two counts one after
+    // the other is racy: a Weak on another thread could upgrade between the
+    // loads and then drop itself. So "lock" the weak count first, which stops
+    // anyone from downgrading while we look at strong
+    fn is_unique(&mut self) -> bool {
+        let inner = self.inner();
+        if inner
+            .weak
+            .compare_exchange(1, LOCKED, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+        {
+            // Acquire pairs with the Release in Drop, so any use of the
+            // value by an Arc that was just dropped happens before we hand
+            // out &mut T
+            let unique = inner.strong.load(Ordering::Acquire) == 1;
+            inner.weak.store(1, Ordering::Release);
+            unique
+        } else {
+            false
+        }
+    }
+
+    fn inner(&self) -> &ArcInner<T> {
+        // SAFETY: the allocation is alive as long as there is an Arc
+        unsafe { self.inner.as_ref() }
+    }
+}
+
+impl<T: Clone> Arc<T> {
+    pub fn make_mut(this: &mut Self) -> &mut T {
+        let inner = this.inner();
+        // Taking strong from 1 to 0 stops Weak's from upgrading while we
+        // decide what to do
+        if inner
+            .strong
+            .compare_exchange(1, 0, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            // Other Arc's exist, clone the value into our own allocation
+            *this = Arc::new((**this).clone());
+        } else if inner.weak.load(Ordering::Relaxed) != 1 {
+            // We were the only Arc but there are Weak's. strong is 0 now so
+            // they will fail to upgrade, move the value out and leave them
+            // with an empty allocation
+            // SAFETY: strong is 0, so no one else can see the value
+            let value = unsafe { ptr::read(&inner.value) };
+            let old = ManuallyDrop::new(std::mem::replace(this, Arc::new(value)));
+            drop(Weak { inner: old.inner });
+        } else {
+            // We were unique all along, put strong back
+            inner.strong.store(1, Ordering::Release);
+        }
+        // SAFETY: we are now the only Arc and there are no Weak's
+        unsafe { &mut (*this.inner.as_ptr()).value }
+    }
+}
+
+impl<T> Clone for Arc<T> {
+    fn clone(&self) -> Self {
+        // Relaxed is fine, we already have an Arc so the value can't go away,
+        // and making a new one doesn't publish anything to other threads
+        let old = self.inner().strong.fetch_add(1, Ordering::Relaxed);
+        if old > MAX_REFCOUNT {
+            std::process::abort();
+        }
+        Arc {
+            inner: self.inner,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<T> std::ops::Deref for Arc<T> {
+    type Target = T;
+    fn deref(&self) -> &Self::Target {
+        &self.inner().value
+    }
+}
+
+impl<T> Drop for Arc<T> {
+    fn drop(&mut self) {
+        // Release so every use of the value through this Arc happens before
+        // the decrement. Whoever takes the count to 0 must see all of them
+        if self.inner().strong.fetch_sub(1, Ordering::Release) != 1 {
+            return;
+        }
+        // We were the last Arc. The fence pairs with the Release decrements
+        // of all the other Arc's, so their uses of the value happen before
+        // we drop it. A fence instead of fetch_sub(AcqRel) means we only pay
+        // for Acquire on the last decrement
+        fence(Ordering::Acquire);
+        // SAFETY: strong is 0, no other Arc exists and no Weak can upgrade
+        unsafe {
+            ptr::drop_in_place(&mut (*self.inner.as_ptr()).value);
+        }
+        drop(Weak { inner: self.inner });
+    }
+}
+
+impl<T> Weak<T> {
+    pub fn upgrade(&self) -> Option<Arc<T>> {
+        let inner = self.inner();
+        let mut s = inner.strong.load(Ordering::Relaxed);
+        // Can't just fetch_add, as it might bring strong back from 0 after
+        // the value has been dropped. Only increment if it isn't 0
+        loop {
+            if s == 0 {
+                return None;
+            }
+            if s > MAX_REFCOUNT {
+                std::process::abort();
+            }
+            // Acquire pairs with the Release in is_unique/make_mut storing
+            // strong back to 1, so we see the changes made through get_mut
+            match inner.strong.compare_exchange_weak(s, s + 1, Ordering::Acquire, Ordering::Relaxed) {
+                Ok(_) => {
+                    return Some(Arc {
+                        inner: self.inner,
+                        _marker: PhantomData,
+                    })
+                }
+                Err(actual) => s = actual,
+            }
+        }
+    }
+
+    pub fn strong_count(&self) -> usize {
+        self.inner().strong.load(Ordering::Relaxed)
+    }
+
+    fn inner(&self) -> &ArcInner<T> {
+        // SAFETY: the allocation is only freed once weak hits 0, and
+        // we are holding one
+        unsafe { self.inner.as_ref() }
+    }
+}
+
+impl<T> Clone for Weak<T> {
+    fn clone(&self) -> Self {
+        // We have a Weak so weak can't be 1 and is_unique can't lock it
+        let old = self.inner().weak.fetch_add(1, Ordering::Relaxed);
+        if old > MAX_REFCOUNT {
+            std::process::abort();
+        }
+        Weak { inner: self.inner }
+    }
+}
+
+impl<T> Drop for Weak<T> {
+    fn drop(&mut self) {
+        // Same protocol as the strong count in Arc's Drop
+        if self.inner().weak.fetch_sub(1, Ordering::Release) != 1 {
+            return;
+        }
+        fence(Ordering::Acquire);
+        // SAFETY: no Arc's and no other Weak's are left, and the value has
+        // already been dropped, so only free the memory
+        unsafe {
+            std::alloc::dealloc(
+                self.inner.as_ptr().cast(),
+                std::alloc::Layout::new::<ArcInner<T>>(),
+            );
+        }
+    }
+}
+
+#[cfg(all(test, not(feature = "loom")))]
+mod test {
+    use super::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+
+    struct D<'a>(&'a AtomicUsize);
+
+    impl Drop for D<'_> {
+        fn drop(&mut self) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn counts() {
+        let a = Arc::new(1);
+        let w = Arc::downgrade(&a);
+        let b = a.clone();
+        assert_eq!(Arc::strong_count(&a), 2);
+        assert_eq!(Arc::weak_count(&a), 1);
+        drop(b);
+        assert_eq!(*w.upgrade().unwrap(), 1);
+        drop(a);
+        assert!(w.upgrade().is_none());
+    }
+
+    #[test]
+    fn get_mut_and_make_mut() {
+        let mut a = Arc::new(String::from("a"));
+        Arc::get_mut(&mut a).unwrap().push('b');
+
+        let b = a.clone();
+        assert!(Arc::get_mut(&mut a).is_none());
+        Arc::make_mut(&mut a).push('c');
+        assert_eq!(*a, "abc");
+        assert_eq!(*b, "ab");
+
+        let w = Arc::downgrade(&a);
+        assert!(Arc::get_mut(&mut a).is_none());
+        Arc::make_mut(&mut a).push('d');
+        assert!(w.upgrade().is_none());
+        assert_eq!(*a, "abcd");
+    }
+
+    #[test]
+    fn try_unwrap_and_into_inner() {
+        let a = Arc::new(5);
+        let b = a.clone();
+        let a = Arc::try_unwrap(a).unwrap_err();
+        assert_eq!(Arc::into_inner(b), None);
+        assert_eq!(Arc::try_unwrap(a).ok(), Some(5));
+
+        let a = Arc::new(7);
+        let b = a.clone();
+        let t = thread::spawn(move || Arc::into_inner(b));
+        let mine = Arc::into_inner(a);
+        let theirs = t.join().unwrap();
+        // exactly one of the two gets the value
+        assert_eq!(mine.or(theirs), Some(7));
+        assert!(mine.is_none() || theirs.is_none());
+    }
+
+    #[test]
+    fn stress_drops_once() {
+        static DROPS: AtomicUsize = AtomicUsize::new(0);
+        for _ in 0..100 {
+            let a = Arc::new(D(&DROPS));
+            let w = Arc::downgrade(&a);
+            let handles: Vec<_> = (0..8)
+                .map(|i| {
+                    let a = a.clone();
+                    let w = w.clone();
+                    thread::spawn(move || {
+                        for _ in 0..100 {
+                            let b = a.clone();
+                            if i % 2 == 0 {
+                                // send a clone on to yet another thread
+                                thread::spawn(move || drop(b)).join().unwrap();
+                            }
+                            if let Some(c) = w.upgrade() {
+                                drop(c);
+                            }
+                        }
+                    })
+                })
+                .collect();
+            drop(a);
+            for h in handles {
+                h.join().unwrap();
+            }
+            assert!(w.upgrade().is_none());
+        }
+        assert_eq!(DROPS.load(Ordering::Relaxed), 100);
+    }
+
+    #[test]
+    fn last_drop_races() {
+        let drops = AtomicUsize::new(0);
+        thread::scope(|s| {
+            for _ in 0..100 {
+                let a = Arc::new(D(&drops));
+                let w = Arc::downgrade(&a);
+                let b = a.clone();
+                s.spawn(move || drop(b));
+                s.spawn(move || drop(w.upgrade()));
+                drop(a);
+            }
+        });
+        assert_eq!(drops.load(Ordering::Relaxed), 100);
+    }
+}
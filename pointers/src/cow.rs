@@ -0,0 +1,174 @@
+// The escape and from_utf8_lossy sketches from lib.rs, for real.
+// Both only allocate when the input actually needs changing, otherwise they
+// hand back the input as Cow::Borrowed
+use std::borrow::Cow;
+
+// Which characters get a backslash in front of them. All of them by default
+#[derive(Debug, Clone, Copy)]
+pub struct Escaper {
+    quotes: bool,
+    backslashes: bool,
+    control: bool,
+}
+
+impl Escaper {
+    pub fn new() -> Self {
+        Escaper {
+            quotes: true,
+            backslashes: true,
+            control: true,
+        }
+    }
+
+    // ' => \'   " => \"
+    pub fn quotes(mut self, on: bool) -> Self {
+        self.quotes = on;
+        self
+    }
+
+    // \ => \\
+    pub fn backslashes(mut self, on: bool) -> Self {
+        self.backslashes = on;
+        self
+    }
+
+    // newline => \n, tab => \t, other control chars => \u{1b}
+    pub fn control(mut self, on: bool) -> Self {
+        self.control = on;
+        self
+    }
+
+    pub fn escape<'a>(&self, s: &'a str) -> Cow<'a, str> {
+        // Find the first character that needs escaping. Everything before it
+        // can be copied over in one go, and if there is none we don't need
+        // to allocate at all
+        let first = match s.char_indices().find(|&(_, c)| self.needs_escape(c)) {
+            Some((i, _)) => i,
+            None => return Cow::Borrowed(s),
+        };
+
+        // escaped strings are usually only a bit longer than the input
+        let mut escaped = String::with_capacity(s.len() + s.len() / 8 + 2);
+        escaped.push_str(&s[..first]);
+        for c in s[first..].chars() {
+            if self.needs_escape(c) {
+                self.push_escaped(&mut escaped, c);
+            } else {
+                escaped.push(c);
+            }
+        }
+        Cow::Owned(escaped)
+    }
+
+    fn needs_escape(&self, c: char) -> bool {
+        match c {
+            '\'' | '"' => self.quotes,
+            '\\' => self.backslashes,
+            c if c.is_control() => self.control,
+            _ => false,
+        }
+    }
+
+    fn push_escaped(&self, out: &mut String, c: char) {
+        out.push('\\');
+        match c {
+            '\n' => out.push('n'),
+            '\r' => out.push('r'),
+            '\t' => out.push('t'),
+            '\0' => out.push('0'),
+            '\'' | '"' | '\\' => out.push(c),
+            c => {
+                use std::fmt::Write;
+                // writing to a String can't fail
+                write!(out, "u{{{:x}}}", c as u32).unwrap();
+            }
+        }
+    }
+}
+
+impl Default for Escaper {
+    fn default() -> Self {
+        Escaper::new()
+    }
+}
+
+// Escape with the default settings
+pub fn escape(s: &str) -> Cow<'_, str> {
+    Escaper::new().escape(s)
+}
+
+// Same as String::from_utf8_lossy: if the bytes are valid utf-8 they are
+// returned as a str without copying, otherwise each invalid sequence is
+// replaced with U+FFFD
+pub fn from_utf8_lossy(bytes: &[u8]) -> Cow<'_, str> {
+    let mut rest = match std::str::from_utf8(bytes) {
+        Ok(s) => return Cow::Borrowed(s),
+        Err(_) => bytes,
+    };
+
+    let mut out = String::with_capacity(bytes.len());
+    loop {
+        match std::str::from_utf8(rest) {
+            Ok(s) => {
+                out.push_str(s);
+                return Cow::Owned(out);
+            }
+            Err(e) => {
+                let (valid, after) = rest.split_at(e.valid_up_to());
+                // SAFETY: from_utf8 just told us this prefix is valid
+                out.push_str(unsafe { std::str::from_utf8_unchecked(valid) });
+                out.push(char::REPLACEMENT_CHARACTER);
+                match e.error_len() {
+                    Some(len) => rest = &after[len..],
+                    // the input ends in the middle of a character
+                    None => return Cow::Owned(out),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{escape, from_utf8_lossy, Escaper};
+    use std::borrow::Cow;
+
+    #[test]
+    fn clean_input_is_borrowed() {
+        assert!(matches!(escape("foo"), Cow::Borrowed("foo")));
+        assert!(matches!(escape(""), Cow::Borrowed("")));
+        assert!(matches!(from_utf8_lossy(b"foo"), Cow::Borrowed("foo")));
+        assert!(matches!(from_utf8_lossy("héllo".as_bytes()), Cow::Borrowed("héllo")));
+    }
+
+    #[test]
+    fn escapes() {
+        assert_eq!(escape(r#"it's "quoted""#), r#"it\'s \"quoted\""#);
+        assert_eq!(escape(r"C:\dir"), r"C:\\dir");
+        assert_eq!(escape("a\nb\tc\0"), r"a\nb\tc\0");
+        assert_eq!(escape("\x1b[0m"), r"\u{1b}[0m");
+        assert_eq!(escape("ünï'cödé"), r"ünï\'cödé");
+    }
+
+    #[test]
+    fn configurable() {
+        let only_quotes = Escaper::new().backslashes(false).control(false);
+        assert!(matches!(only_quotes.escape("a\\b\n"), Cow::Borrowed(_)));
+        assert_eq!(only_quotes.escape("'\\"), "\\'\\");
+
+        let no_quotes = Escaper::new().quotes(false);
+        assert!(matches!(no_quotes.escape("'\""), Cow::Borrowed(_)));
+        assert_eq!(no_quotes.escape("'\n"), "'\\n");
+    }
+
+    #[test]
+    fn lossy() {
+        assert_eq!(from_utf8_lossy(b"hello \xF0\x90\x80world"), "hello \u{FFFD}world");
+        assert_eq!(from_utf8_lossy(b"\xFF\xFEab"), "\u{FFFD}\u{FFFD}ab");
+        assert_eq!(from_utf8_lossy(b"ab\xE2\x82"), "ab\u{FFFD}");
+        // same answers as std
+        for bytes in [&b"\xC0\x80x"[..], b"\xED\xA0\x80", b"a\x80b\xC2"] {
+            assert_eq!(from_utf8_lossy(bytes), String::from_utf8_lossy(bytes));
+        }
+    }
+}
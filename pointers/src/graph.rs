@@ -0,0 +1,453 @@
+// A directed graph built out of the crate's own Rc, Weak and RefCell.
+//
+// The graph keeps every node in an arena (a Vec indexed by NodeId), and each
+// node owns its outgoing edges, which point at their targets with an Rc.
+// Every target also keeps a Weak back-edge to each of its sources, so edges
+// can be removed from either end without searching the whole graph.
+//
+// A cycle in the graph is a cycle of Rc's, which would never be freed on its
+// own (see the tree test in rc.rs). So dropping the Graph clears all the edges
+// first, after which the arena holds the only Rc to each node.
+use crate::rc::{Rc, Weak};
+use crate::refcell::{Ref, RefCell, RefMut};
+use std::collections::{HashSet, VecDeque};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct NodeId(usize);
+
+type NodeRef<N, E> = Rc<RefCell<Node<N, E>>>;
+
+struct Node<N, E> {
+    id: NodeId,
+    data: N,
+    edges: Vec<Edge<N, E>>,
+    // the nodes that have an edge to us
+    incoming: Vec<Weak<RefCell<Node<N, E>>>>,
+}
+
+struct Edge<N, E> {
+    to: NodeRef<N, E>,
+    weight: E,
+}
+
+pub struct Graph<N, E> {
+    // removed nodes leave a None behind so the other NodeId's stay valid
+    nodes: Vec<Option<NodeRef<N, E>>>,
+    node_count: usize,
+    edge_count: usize,
+}
+
+impl<N, E> Graph<N, E> {
+    pub fn new() -> Self {
+        Graph {
+            nodes: Vec::new(),
+            node_count: 0,
+            edge_count: 0,
+        }
+    }
+
+    pub fn node_count(&self) -> usize {
+        self.node_count
+    }
+
+    pub fn edge_count(&self) -> usize {
+        self.edge_count
+    }
+
+    pub fn add_node(&mut self, data: N) -> NodeId {
+        let id = NodeId(self.nodes.len());
+        self.nodes.push(Some(Rc::new(RefCell::new(Node {
+            id,
+            data,
+            edges: Vec::new(),
+            incoming: Vec::new(),
+        }))));
+        self.node_count += 1;
+        id
+    }
+
+    // Removes the node along with every edge to and from it
+    pub fn remove_node(&mut self, id: NodeId) -> Option<N> {
+        let rc = self.nodes.get_mut(id.0)?.take()?;
+        {
+            let mut node = rc.borrow_mut();
+            let edges = std::mem::take(&mut node.edges);
+            let incoming = std::mem::take(&mut node.incoming);
+            self.edge_count -= edges.len();
+            for edge in edges {
+                // a self loop, we are already borrowed and dropping it anyway
+                if !Rc::ptr_eq(&edge.to, &rc) {
+                    edge.to.borrow_mut().incoming.retain(|w| !points_to(w, &rc));
+                }
+            }
+            for source in incoming.iter().filter_map(Weak::upgrade) {
+                if !Rc::ptr_eq(&source, &rc) {
+                    let mut source = source.borrow_mut();
+                    let before = source.edges.len();
+                    source.edges.retain(|e| !Rc::ptr_eq(&e.to, &rc));
+                    self.edge_count -= before - source.edges.len();
+                }
+            }
+        }
+        self.node_count -= 1;
+        // All the edges are gone and we don't hand out Rc's, so the arena
+        // was holding the only one
+        let node = match Rc::try_unwrap(rc) {
+            Ok(node) => node,
+            Err(_) => unreachable!("graph node still referenced after removing its edges"),
+        };
+        Some(node.into_inner().data)
+    }
+
+    // Panics if either node doesn't exist. Returns the old weight if there
+    // already was an edge between them
+    pub fn add_edge(&mut self, from: NodeId, to: NodeId, weight: E) -> Option<E> {
+        // clones so self isn't borrowed while we update the counts
+        let from = self.get(from).clone();
+        let to = self.get(to).clone();
+        let mut source = from.borrow_mut();
+        if let Some(edge) = source.edges.iter_mut().find(|e| Rc::ptr_eq(&e.to, &to)) {
+            return Some(std::mem::replace(&mut edge.weight, weight));
+        }
+        source.edges.push(Edge {
+            to: to.clone(),
+            weight,
+        });
+        self.edge_count += 1;
+        // source is still borrowed if this is a self loop
+        if Rc::ptr_eq(&from, &to) {
+            source.incoming.push(Rc::downgrade(&from));
+        } else {
+            to.borrow_mut().incoming.push(Rc::downgrade(&from));
+        }
+        None
+    }
+
+    pub fn remove_edge(&mut self, from: NodeId, to: NodeId) -> Option<E> {
+        let from = self.try_get(from)?.clone();
+        let to = self.try_get(to)?.clone();
+        let mut source = from.borrow_mut();
+        let i = source.edges.iter().position(|e| Rc::ptr_eq(&e.to, &to))?;
+        let edge = source.edges.remove(i);
+        self.edge_count -= 1;
+        let mut target = if Rc::ptr_eq(&from, &to) {
+            source
+        } else {
+            drop(source);
+            to.borrow_mut()
+        };
+        // only remove one back-edge, there is one per edge
+        if let Some(j) = target.incoming.iter().position(|w| points_to(w, &from)) {
+            target.incoming.swap_remove(j);
+        }
+        Some(edge.weight)
+    }
+
+    pub fn contains_node(&self, id: NodeId) -> bool {
+        self.try_get(id).is_some()
+    }
+
+    pub fn node(&self, id: NodeId) -> Option<Ref<'_, N>> {
+        Some(Ref::map(self.try_get(id)?.borrow(), |n| &n.data))
+    }
+
+    pub fn node_mut(&mut self, id: NodeId) -> Option<RefMut<'_, N>> {
+        Some(RefMut::map(self.try_get(id)?.borrow_mut(), |n| &mut n.data))
+    }
+
+    pub fn edge(&self, from: NodeId, to: NodeId) -> Option<Ref<'_, E>> {
+        let to = self.try_get(to)?;
+        let source = self.try_get(from)?.borrow();
+        Ref::filter_map(source, |n| {
+            n.edges
+                .iter()
+                .find(|e| Rc::ptr_eq(&e.to, to))
+                .map(|e| &e.weight)
+        })
+        .ok()
+    }
+
+    // The targets of the edges out of id, in the order they were added
+    pub fn neighbors(&self, id: NodeId) -> Vec<NodeId> {
+        self.get(id)
+            .borrow()
+            .edges
+            .iter()
+            .map(|e| e.to.borrow().id)
+            .collect()
+    }
+
+    // The sources of the edges into id
+    pub fn predecessors(&self, id: NodeId) -> Vec<NodeId> {
+        self.get(id)
+            .borrow()
+            .incoming
+            .iter()
+            .filter_map(Weak::upgrade)
+            .map(|n| n.borrow().id)
+            .collect()
+    }
+
+    pub fn node_ids(&self) -> impl Iterator<Item = NodeId> + '_ {
+        self.nodes.iter().flatten().map(|n| n.borrow().id)
+    }
+
+    // Breadth first from start, every reachable node once
+    pub fn bfs(&self, start: NodeId) -> Bfs<'_, N, E> {
+        self.get(start);
+        Bfs {
+            graph: self,
+            queue: VecDeque::from([start]),
+            visited: HashSet::from([start]),
+        }
+    }
+
+    // Depth first (preorder) from start, every reachable node once
+    pub fn dfs(&self, start: NodeId) -> Dfs<'_, N, E> {
+        self.get(start);
+        Dfs {
+            graph: self,
+            stack: vec![start],
+            visited: HashSet::new(),
+        }
+    }
+
+    // Whether following the edges can ever lead back to where you started
+    pub fn has_cycle(&self) -> bool {
+        #[derive(Clone, Copy, PartialEq)]
+        enum Mark {
+            Unvisited,
+            // on the current path
+            InProgress,
+            Done,
+        }
+        let mut marks = vec![Mark::Unvisited; self.nodes.len()];
+        for root in self.node_ids() {
+            if marks[root.0] != Mark::Unvisited {
+                continue;
+            }
+            // iterative so big graphs don't overflow the stack. Each entry is
+            // a node and how many of its neighbors we have looked at
+            let mut stack = vec![(root, self.neighbors(root), 0)];
+            marks[root.0] = Mark::InProgress;
+            while let Some((id, neighbors, i)) = stack.last_mut() {
+                match neighbors.get(*i) {
+                    Some(&next) => {
+                        *i += 1;
+                        match marks[next.0] {
+                            Mark::InProgress => return true,
+                            Mark::Done => {}
+                            Mark::Unvisited => {
+                                marks[next.0] = Mark::InProgress;
+                                let next_neighbors = self.neighbors(next);
+                                stack.push((next, next_neighbors, 0));
+                            }
+                        }
+                    }
+                    None => {
+                        marks[id.0] = Mark::Done;
+                        stack.pop();
+                    }
+                }
+            }
+        }
+        false
+    }
+
+    fn try_get(&self, id: NodeId) -> Option<&NodeRef<N, E>> {
+        self.nodes.get(id.0)?.as_ref()
+    }
+
+    #[track_caller]
+    fn get(&self, id: NodeId) -> &NodeRef<N, E> {
+        match self.try_get(id) {
+            Some(node) => node,
+            None => panic!("no node with id {:?} in the graph", id),
+        }
+    }
+}
+
+fn points_to<T>(weak: &Weak<T>, rc: &Rc<T>) -> bool {
+    weak.upgrade().is_some_and(|w| Rc::ptr_eq(&w, rc))
+}
+
+impl<N, E> Default for Graph<N, E> {
+    fn default() -> Self {
+        Graph::new()
+    }
+}
+
+impl<N, E> Drop for Graph<N, E> {
+    fn drop(&mut self) {
+        // Break the Rc cycles, otherwise any cycle in the graph leaks
+        for node in self.nodes.iter().flatten() {
+            let mut node = node.borrow_mut();
+            // take the edges out first, dropping them may drop the weights,
+            // which shouldn't happen while node is borrowed
+            let edges = std::mem::take(&mut node.edges);
+            drop(node);
+            drop(edges);
+        }
+    }
+}
+
+pub struct Bfs<'g, N, E> {
+    graph: &'g Graph<N, E>,
+    queue: VecDeque<NodeId>,
+    visited: HashSet<NodeId>,
+}
+
+impl<N, E> Iterator for Bfs<'_, N, E> {
+    type Item = NodeId;
+    fn next(&mut self) -> Option<NodeId> {
+        let id = self.queue.pop_front()?;
+        for next in self.graph.neighbors(id) {
+            if self.visited.insert(next) {
+                self.queue.push_back(next);
+            }
+        }
+        Some(id)
+    }
+}
+
+pub struct Dfs<'g, N, E> {
+    graph: &'g Graph<N, E>,
+    stack: Vec<NodeId>,
+    visited: HashSet<NodeId>,
+}
+
+impl<N, E> Iterator for Dfs<'_, N, E> {
+    type Item = NodeId;
+    fn next(&mut self) -> Option<NodeId> {
+        while let Some(id) = self.stack.pop() {
+            if !self.visited.insert(id) {
+                continue;
+            }
+            // reversed so the first neighbor is visited first
+            for next in self.graph.neighbors(id).into_iter().rev() {
+                if !self.visited.contains(&next) {
+                    self.stack.push(next);
+                }
+            }
+            return Some(id);
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Graph;
+    use std::cell::Cell;
+
+    struct D<'a>(&'a Cell<usize>);
+
+    impl Drop for D<'_> {
+        fn drop(&mut self) {
+            self.0.set(self.0.get() + 1);
+        }
+    }
+
+    #[test]
+    fn nodes_and_edges() {
+        let mut g = Graph::new();
+        let a = g.add_node("a");
+        let b = g.add_node("b");
+        let c = g.add_node("c");
+        assert_eq!(g.add_edge(a, b, 1), None);
+        assert_eq!(g.add_edge(a, c, 2), None);
+        assert_eq!(g.add_edge(a, b, 3), Some(1));
+        assert_eq!(g.edge_count(), 2);
+        assert_eq!(*g.edge(a, b).unwrap(), 3);
+        assert!(g.edge(b, a).is_none());
+        assert_eq!(g.neighbors(a), vec![b, c]);
+        assert_eq!(g.predecessors(c), vec![a]);
+
+        *g.node_mut(c).unwrap() = "C";
+        assert_eq!(*g.node(c).unwrap(), "C");
+
+        assert_eq!(g.remove_edge(a, b), Some(3));
+        assert_eq!(g.remove_edge(a, b), None);
+        assert!(g.predecessors(b).is_empty());
+        assert_eq!(g.edge_count(), 1);
+    }
+
+    #[test]
+    fn remove_node() {
+        let mut g = Graph::new();
+        let a = g.add_node(String::from("a"));
+        let b = g.add_node(String::from("b"));
+        let c = g.add_node(String::from("c"));
+        g.add_edge(a, b, ());
+        g.add_edge(b, c, ());
+        g.add_edge(c, b, ());
+        g.add_edge(b, b, ());
+        assert_eq!(g.remove_node(b).as_deref(), Some("b"));
+        assert_eq!(g.remove_node(b), None);
+        assert!(!g.contains_node(b));
+        assert_eq!(g.node_count(), 2);
+        assert_eq!(g.edge_count(), 0);
+        assert!(g.neighbors(a).is_empty());
+        assert!(g.predecessors(c).is_empty());
+        assert_eq!(g.node_ids().collect::<Vec<_>>(), vec![a, c]);
+    }
+
+    #[test]
+    fn traversals() {
+        //   0 -> 1 -> 3
+        //   |         ^
+        //   v         |
+        //   2 --------+     4 (unreachable)
+        let mut g = Graph::new();
+        let n: Vec<_> = (0..5).map(|i| g.add_node(i)).collect();
+        g.add_edge(n[0], n[1], ());
+        g.add_edge(n[0], n[2], ());
+        g.add_edge(n[1], n[3], ());
+        g.add_edge(n[2], n[3], ());
+
+        let bfs: Vec<_> = g.bfs(n[0]).map(|id| *g.node(id).unwrap()).collect();
+        assert_eq!(bfs, vec![0, 1, 2, 3]);
+        let dfs: Vec<_> = g.dfs(n[0]).map(|id| *g.node(id).unwrap()).collect();
+        assert_eq!(dfs, vec![0, 1, 3, 2]);
+    }
+
+    #[test]
+    fn cycles() {
+        let mut g = Graph::new();
+        let a = g.add_node(());
+        let b = g.add_node(());
+        let c = g.add_node(());
+        g.add_edge(a, b, ());
+        g.add_edge(b, c, ());
+        g.add_edge(a, c, ());
+        // a diamond is not a cycle
+        assert!(!g.has_cycle());
+        g.add_edge(c, a, ());
+        assert!(g.has_cycle());
+        assert_eq!(g.bfs(a).count(), 3);
+        g.remove_edge(c, a);
+        assert!(!g.has_cycle());
+        g.add_edge(b, b, ());
+        assert!(g.has_cycle());
+    }
+
+    #[test]
+    fn drop_frees_every_node() {
+        let node_drops = Cell::new(0);
+        let edge_drops = Cell::new(0);
+        {
+            let mut g = Graph::new();
+            let n: Vec<_> = (0..4).map(|_| g.add_node(D(&node_drops))).collect();
+            for i in 0..4 {
+                // a ring plus self loops, all cycles
+                g.add_edge(n[i], n[(i + 1) % 4], D(&edge_drops));
+                g.add_edge(n[i], n[i], D(&edge_drops));
+            }
+            drop(g.remove_node(n[0]));
+            assert_eq!(node_drops.get(), 1);
+            assert_eq!(edge_drops.get(), 3);
+        }
+        assert_eq!(node_drops.get(), 4);
+        assert_eq!(edge_drops.get(), 8);
+    }
+}
@@ -0,0 +1,307 @@
+// Rc keeps its counts next to the value in an RcInner that it allocates
+// itself. Objects that come from C often already have a refcount field in them
+// (and retain/release functions to go with it), and have been allocated by the
+// C side. Wrapping one of those in an Rc would mean a second allocation and
+// two counts that don't know about each other.
+//
+// An intrusive pointer instead asks the object to count itself. IntrusiveRc is
+// then just a pointer to the object, and the same pointer can be handed back
+// and forth between Rust and C with into_raw/from_raw, each side doing its own
+// retain/release on the one count.
+use std::fmt;
+use std::marker::PhantomData;
+use std::mem::ManuallyDrop;
+use std::ptr::{self, NonNull};
+
+/// # Safety
+///
+/// `inc_ref` and `dec_ref` must maintain a count of the references to the
+/// object, and the object must stay alive (and not move) for as long as that
+/// count is above zero. Once `dec_ref` takes it to zero it is responsible for
+/// freeing the object, however it was allocated.
+pub unsafe trait RefCounted {
+    // Called on clone. Takes &self because we always have a live reference
+    // at that point. The count changes behind a shared reference, so like the
+    // counts in RcInner it has to be in a Cell (or be an atomic)
+    fn inc_ref(&self);
+
+    /// # Safety
+    ///
+    /// `this` must point to a live object, and the caller gives up the
+    /// reference it was holding. `this` may be dangling when this returns.
+    // Takes a pointer rather than &self, since the object may free itself in
+    // here, and a &self would then outlive what it points to
+    unsafe fn dec_ref(this: NonNull<Self>);
+}
+
+// The count lives in T, and nothing says it is atomic, so like Rc this is
+// neither Send nor Sync (NonNull is neither). A thread safe object would need
+// its own wrapper that says so
+pub struct IntrusiveRc<T: RefCounted> {
+    ptr: NonNull<T>,
+    // we own (a share of) a T, for the drop check
+    _marker: PhantomData<T>,
+}
+
+impl<T: RefCounted> IntrusiveRc<T> {
+    /// Takes over one reference the caller already holds, the count is not
+    /// touched. This is the way back from `into_raw`, or from a C function
+    /// that returns a new reference (`obj_new`, `obj_copy`...).
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must point to a live object, and the caller must own one of the
+    /// references counted by it. That reference now belongs to the IntrusiveRc.
+    pub unsafe fn from_raw(ptr: *const T) -> Self {
+        IntrusiveRc {
+            ptr: NonNull::new_unchecked(ptr as *mut T),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Takes a new reference, for pointers we are only borrowing, like an
+    /// argument C passes to a callback or something returned by a `get_`
+    /// function.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must point to a live object.
+    pub unsafe fn from_borrowed_raw(ptr: *const T) -> Self {
+        (*ptr).inc_ref();
+        Self::from_raw(ptr)
+    }
+
+    // Gives up ownership without touching the count, for handing a reference
+    // over to C. It has to come back through from_raw (or be released on the
+    // C side) or the object is leaked
+    pub fn into_raw(this: Self) -> *const T {
+        let this = ManuallyDrop::new(this);
+        this.ptr.as_ptr()
+    }
+
+    // For passing the object to C while we keep our reference
+    pub fn as_ptr(this: &Self) -> *const T {
+        this.ptr.as_ptr()
+    }
+
+    pub fn ptr_eq(this: &Self, other: &Self) -> bool {
+        ptr::eq(this.ptr.as_ptr(), other.ptr.as_ptr())
+    }
+}
+
+impl<T: RefCounted> Clone for IntrusiveRc<T> {
+    fn clone(&self) -> Self {
+        self.inc_ref();
+        IntrusiveRc {
+            ptr: self.ptr,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<T: RefCounted> std::ops::Deref for IntrusiveRc<T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        // SAFETY: we hold a reference, so the count is above zero and the
+        // object is alive
+        unsafe { self.ptr.as_ref() }
+    }
+}
+
+impl<T: RefCounted> Drop for IntrusiveRc<T> {
+    fn drop(&mut self) {
+        // SAFETY: we hold a reference and give it up here. Unlike Rc we don't
+        // drop anything ourselves, whoever implemented dec_ref knows how the
+        // object was allocated
+        unsafe { T::dec_ref(self.ptr) };
+    }
+}
+
+impl<T: RefCounted + fmt::Debug> fmt::Debug for IntrusiveRc<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<T: RefCounted> fmt::Pointer for IntrusiveRc<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Pointer::fmt(&self.ptr, f)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{IntrusiveRc, RefCounted};
+    use crate::cell::Cell;
+    use std::ptr::NonNull;
+
+    // Pretend C library. In real code these would be in an extern "C" block
+    // generated by bindgen, like in libsodium-sys
+    mod c {
+        use std::cell::Cell;
+        use std::os::raw::c_int;
+
+        // Cell<c_int> has the same layout as c_int, C doesn't know the
+        // difference, but Rust now knows it can change under a &Obj
+        #[repr(C)]
+        pub struct Obj {
+            refs: Cell<c_int>,
+            pub value: c_int,
+        }
+
+        thread_local! {
+            pub static FREED: Cell<usize> = const { Cell::new(0) };
+        }
+
+        pub extern "C" fn obj_new(value: c_int) -> *mut Obj {
+            Box::into_raw(Box::new(Obj {
+                refs: Cell::new(1),
+                value,
+            }))
+        }
+
+        pub unsafe extern "C" fn obj_retain(obj: *const Obj) {
+            let refs = &(*obj).refs;
+            refs.set(refs.get() + 1);
+        }
+
+        pub unsafe extern "C" fn obj_release(obj: *mut Obj) {
+            let refs = &(*obj).refs;
+            refs.set(refs.get() - 1);
+            if refs.get() == 0 {
+                drop(Box::from_raw(obj));
+                FREED.with(|f| f.set(f.get() + 1));
+            }
+        }
+
+        pub unsafe extern "C" fn obj_refs(obj: *const Obj) -> c_int {
+            (*obj).refs.get()
+        }
+
+        // C keeping a reference around, say in a callback table
+        thread_local! {
+            pub static STASH: Cell<*mut Obj> = const { Cell::new(std::ptr::null_mut()) };
+        }
+
+        pub unsafe extern "C" fn obj_stash(obj: *mut Obj) {
+            obj_retain(obj);
+            STASH.with(|s| s.set(obj));
+        }
+
+        pub extern "C" fn obj_unstash() -> *mut Obj {
+            STASH.with(|s| s.replace(std::ptr::null_mut()))
+        }
+    }
+
+    // SAFETY: retain/release keep the count, and release frees the object
+    // when it reaches zero
+    unsafe impl RefCounted for c::Obj {
+        fn inc_ref(&self) {
+            unsafe { c::obj_retain(self) };
+        }
+
+        unsafe fn dec_ref(this: NonNull<Self>) {
+            c::obj_release(this.as_ptr());
+        }
+    }
+
+    fn refs(obj: &IntrusiveRc<c::Obj>) -> i32 {
+        unsafe { c::obj_refs(IntrusiveRc::as_ptr(obj)) }
+    }
+
+    fn freed() -> usize {
+        c::FREED.with(|f| f.get())
+    }
+
+    #[test]
+    fn clone_and_drop() {
+        let before = freed();
+        let a = unsafe { IntrusiveRc::from_raw(c::obj_new(7)) };
+        assert_eq!(refs(&a), 1);
+        let b = a.clone();
+        assert_eq!(refs(&a), 2);
+        assert!(IntrusiveRc::ptr_eq(&a, &b));
+        assert_eq!(b.value, 7);
+        drop(a);
+        assert_eq!(refs(&b), 1);
+        assert_eq!(freed(), before);
+        drop(b);
+        assert_eq!(freed(), before + 1);
+    }
+
+    #[test]
+    fn shared_with_c() {
+        let before = freed();
+        let a = unsafe { IntrusiveRc::from_raw(c::obj_new(1)) };
+        // C takes its own reference, no second allocation anywhere
+        unsafe { c::obj_stash(IntrusiveRc::as_ptr(&a) as *mut _) };
+        assert_eq!(refs(&a), 2);
+        drop(a);
+        assert_eq!(freed(), before);
+
+        // C hands its reference back to us
+        let back = unsafe { IntrusiveRc::from_raw(c::obj_unstash()) };
+        assert_eq!(back.value, 1);
+        assert_eq!(refs(&back), 1);
+        drop(back);
+        assert_eq!(freed(), before + 1);
+    }
+
+    #[test]
+    fn raw_round_trip() {
+        let before = freed();
+        let a = unsafe { IntrusiveRc::from_raw(c::obj_new(2)) };
+        let raw = IntrusiveRc::into_raw(a.clone());
+        assert_eq!(refs(&a), 2);
+        let b = unsafe { IntrusiveRc::from_raw(raw) };
+        assert_eq!(refs(&a), 2);
+
+        // borrowing doesn't steal the reference the pointer came with
+        let c = unsafe { IntrusiveRc::from_borrowed_raw(IntrusiveRc::as_ptr(&b)) };
+        assert_eq!(refs(&a), 3);
+        drop((a, b, c));
+        assert_eq!(freed(), before + 1);
+    }
+
+    // Rust side objects can be counted intrusively as well
+    struct Node<'a> {
+        refs: Cell<usize>,
+        drops: &'a Cell<usize>,
+    }
+
+    impl Drop for Node<'_> {
+        fn drop(&mut self) {
+            self.drops.set(self.drops.get() + 1);
+        }
+    }
+
+    unsafe impl RefCounted for Node<'_> {
+        fn inc_ref(&self) {
+            self.refs.set(self.refs.get() + 1);
+        }
+
+        unsafe fn dec_ref(this: NonNull<Self>) {
+            let refs = &this.as_ref().refs;
+            refs.set(refs.get() - 1);
+            if refs.get() == 0 {
+                drop(Box::from_raw(this.as_ptr()));
+            }
+        }
+    }
+
+    #[test]
+    fn rust_allocated() {
+        let drops = Cell::new(0);
+        let node = Box::new(Node {
+            refs: Cell::new(1),
+            drops: &drops,
+        });
+        let a = unsafe { IntrusiveRc::from_raw(Box::into_raw(node)) };
+        let b = a.clone();
+        assert_eq!(a.refs.get(), 2);
+        drop(a);
+        assert_eq!(drops.get(), 0);
+        drop(b);
+        assert_eq!(drops.get(), 1);
+    }
+}
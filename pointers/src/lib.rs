@@ -1,9 +1,20 @@
+#![cfg_attr(feature = "unsize", feature(coerce_unsized, unsize))]
+
 pub mod cell;
 pub mod refcell;
 pub mod rc;
+pub mod arc;
+pub mod once;
+pub mod cow;
+pub mod graph;
+pub mod intrusive;
+
+#[cfg(test)]
+mod soundness;
 
 use std::borrow::Cow;
 
+// These are implemented for real in the cow module
 /*
 Let's say this function is looking to escape special charecters,
 it is very wasteful to return String for a string like "foo" where
@@ -0,0 +1,257 @@
+// A cell that can only be written to once. After that it hands out &T, which
+// Cell can't do, but it never needs to give out &mut T like RefCell, since
+// the value never changes once it is set. So no borrow counting is needed
+//
+// Like Cell and RefCell this is !Sync (UnsafeCell), so it is single threaded.
+// Classic use is lazily computing a value in a thread local
+use crate::cell::Cell;
+use std::cell::UnsafeCell;
+
+pub struct OnceCell<T> {
+    value: UnsafeCell<Option<T>>,
+    // Set while the closure passed to get_or_init is running. If the closure
+    // tries to initialise the same cell again, we would end up writing to
+    // value while the outer call is about to write to it as well, and
+    // any &T handed out in between would be invalidated
+    initializing: Cell<bool>,
+}
+
+impl<T> OnceCell<T> {
+    pub const fn new() -> Self {
+        OnceCell {
+            value: UnsafeCell::new(None),
+            initializing: Cell::new(false),
+        }
+    }
+
+    pub fn get(&self) -> Option<&T> {
+        // SAFETY: value is only written to while it is None, and we only give
+        // out references once it is Some, so this never aliases a write
+        unsafe { &*self.value.get() }.as_ref()
+    }
+
+    // Ok if the cell was empty, otherwise the value is handed back
+    pub fn set(&self, value: T) -> Result<(), T> {
+        if self.get().is_some() {
+            return Err(value);
+        }
+        self.assert_not_initializing();
+        // SAFETY: the cell is empty so there are no references into it, and
+        // no initialisation is in progress that will write to it later
+        unsafe { *self.value.get() = Some(value) };
+        Ok(())
+    }
+
+    pub fn get_or_init<F>(&self, f: F) -> &T
+    where
+        F: FnOnce() -> T,
+    {
+        match self.get_or_try_init(|| Ok::<T, std::convert::Infallible>(f())) {
+            Ok(value) => value,
+            Err(e) => match e {},
+        }
+    }
+
+    // If f fails, the cell stays empty and the next call gets to try again
+    pub fn get_or_try_init<F, E>(&self, f: F) -> Result<&T, E>
+    where
+        F: FnOnce() -> Result<T, E>,
+    {
+        if let Some(value) = self.get() {
+            return Ok(value);
+        }
+        self.assert_not_initializing();
+
+        self.initializing.set(true);
+        // Reset the flag even if f panics, so the cell can still be used
+        // by whoever catches the panic
+        let guard = ResetOnDrop(&self.initializing);
+        let value = f();
+        drop(guard);
+
+        let value = value?;
+        // SAFETY: the flag stopped f from filling the cell, so it is still
+        // empty and there are no references into it
+        unsafe { *self.value.get() = Some(value) };
+        Ok(self.get().unwrap())
+    }
+
+    // &mut self, so no need to worry about references or initialisation
+    pub fn get_mut(&mut self) -> Option<&mut T> {
+        self.value.get_mut().as_mut()
+    }
+
+    pub fn take(&mut self) -> Option<T> {
+        self.value.get_mut().take()
+    }
+
+    pub fn into_inner(self) -> Option<T> {
+        self.value.into_inner()
+    }
+
+    #[track_caller]
+    fn assert_not_initializing(&self) {
+        if self.initializing.get() {
+            panic!("reentrant init: OnceCell initialised from within its own initialiser");
+        }
+    }
+}
+
+impl<T> Default for OnceCell<T> {
+    fn default() -> Self {
+        OnceCell::new()
+    }
+}
+
+struct ResetOnDrop<'a>(&'a Cell<bool>);
+
+impl Drop for ResetOnDrop<'_> {
+    fn drop(&mut self) {
+        self.0.set(false);
+    }
+}
+
+// A value that is computed the first time it is dereferenced.
+// fn() -> T as the default so it can be named in a static/thread_local
+// without having to spell out the closure type
+pub struct Lazy<T, F = fn() -> T> {
+    cell: OnceCell<T>,
+    // taken out the first time it is used. If it is missing and the cell is
+    // still empty, the initialiser panicked
+    init: Cell<Option<F>>,
+}
+
+impl<T, F: FnOnce() -> T> Lazy<T, F> {
+    pub const fn new(f: F) -> Self {
+        Lazy {
+            cell: OnceCell::new(),
+            init: Cell::new(Some(f)),
+        }
+    }
+
+    // An associated function rather than a method so it doesn't shadow
+    // a method on T called force
+    pub fn force(this: &Lazy<T, F>) -> &T {
+        this.cell.get_or_init(|| match this.init.take() {
+            Some(f) => f(),
+            None => panic!("Lazy instance has previously been poisoned"),
+        })
+    }
+}
+
+impl<T, F: FnOnce() -> T> std::ops::Deref for Lazy<T, F> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        Lazy::force(self)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Lazy, OnceCell};
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+
+    #[test]
+    fn set_and_get() {
+        let c = OnceCell::new();
+        assert!(c.get().is_none());
+        assert_eq!(c.set(1), Ok(()));
+        assert_eq!(c.set(2), Err(2));
+        assert_eq!(c.get(), Some(&1));
+        assert_eq!(c.into_inner(), Some(1));
+    }
+
+    #[test]
+    fn get_or_init_runs_once() {
+        let c = OnceCell::new();
+        let mut calls = 0;
+        let a = c.get_or_init(|| {
+            calls += 1;
+            String::from("a")
+        });
+        assert_eq!(a, "a");
+        assert_eq!(c.get_or_init(|| unreachable!()), "a");
+        assert_eq!(calls, 1);
+    }
+
+    #[test]
+    fn get_or_try_init() {
+        let c: OnceCell<i32> = OnceCell::new();
+        assert_eq!(c.get_or_try_init(|| Err("nope")), Err("nope"));
+        assert!(c.get().is_none());
+        assert_eq!(c.get_or_try_init(|| Ok::<_, ()>(3)), Ok(&3));
+    }
+
+    #[test]
+    fn get_mut_and_take() {
+        let mut c = OnceCell::new();
+        c.set(vec![1]).unwrap();
+        c.get_mut().unwrap().push(2);
+        assert_eq!(c.take(), Some(vec![1, 2]));
+        assert!(c.get().is_none());
+        c.set(vec![]).unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "reentrant init")]
+    fn reentrant_get_or_init() {
+        let c = OnceCell::new();
+        c.get_or_init(|| *c.get_or_init(|| 1) + 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "reentrant init")]
+    fn reentrant_set() {
+        let c = OnceCell::new();
+        c.get_or_init(|| {
+            let _ = c.set(1);
+            2
+        });
+    }
+
+    #[test]
+    fn usable_after_panicking_init() {
+        let c = OnceCell::new();
+        let r = catch_unwind(AssertUnwindSafe(|| c.get_or_init(|| -> i32 { panic!("boom") })));
+        assert!(r.is_err());
+        assert!(c.get().is_none());
+        assert_eq!(*c.get_or_init(|| 5), 5);
+    }
+
+    #[test]
+    fn lazy() {
+        let mut calls = 0;
+        let l = Lazy::new(|| {
+            calls += 1;
+            vec![1, 2, 3]
+        });
+        assert_eq!(l.len(), 3);
+        assert_eq!(Lazy::force(&l)[0], 1);
+        drop(l);
+        assert_eq!(calls, 1);
+    }
+
+    #[test]
+    fn lazy_thread_local() {
+        thread_local! {
+            static SQUARES: Lazy<Vec<u64>> = const { Lazy::new(|| (0..10).map(|x| x * x).collect()) };
+        }
+        assert_eq!(SQUARES.with(|s| s[9]), 81);
+    }
+
+    #[test]
+    fn lazy_reentrant() {
+        thread_local! {
+            static SELF_REF: Lazy<u32> = const { Lazy::new(|| SELF_REF.with(|l| **l) + 1) };
+        }
+        let r = catch_unwind(|| SELF_REF.with(|l| **l));
+        assert!(r.is_err());
+        // the initialiser was used up by the panicking attempt
+        let r = catch_unwind(|| SELF_REF.with(|l| **l));
+        let msg = r.unwrap_err();
+        assert_eq!(
+            msg.downcast_ref::<&str>(),
+            Some(&"Lazy instance has previously been poisoned")
+        );
+    }
+}
@@ -1,29 +1,57 @@
 // Rc does not provide mutability
 // It just allows multiple shared references to a thing
 // Rc is not thread safe!
-use crate::cell::two::Cell;
-use std::ptr::NonNull;
+use crate::cell::Cell;
+use std::alloc::{alloc, dealloc, handle_alloc_error, Layout};
+use std::borrow::Borrow;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::ptr::{self, NonNull};
 use std::marker::PhantomData;
+use std::mem::{ManuallyDrop, MaybeUninit};
 
-struct RcInner<T> {
+// repr(C) so the counts always come first and value last. That way the offset
+// of value only depends on its alignment, which we need to know to allocate
+// an RcInner<[T]>/RcInner<str> ourselves and to find the RcInner from a *const T
+// Only the last field of a struct is allowed to be unsized
+#[repr(C)]
+struct RcInner<T: ?Sized> {
+    // Number of Rc's pointing at the allocation. When it hits 0, value is dropped
+    strong: Cell<usize>,
+    // Number of Weak's pointing at the allocation, plus one that is held
+    // collectively by all the Rc's. When it hits 0, the allocation is freed
+    weak: Cell<usize>,
     value: T,
-    refcount: Cell<usize>,
 }
 
-pub struct Rc<T> {
+// T: ?Sized means T is allowed to be a dynamically sized type like str, [u8]
+// or dyn Fn(). A pointer to one of those is a "fat" pointer, the address
+// plus the length or the vtable, so NonNull<RcInner<T>> carries that as well
+pub struct Rc<T: ?Sized> {
     // Rc is !Send because NonNull is !Send
     inner: NonNull<RcInner<T>>,
-    // This doesn't work because closing the struct clones the refcount as well 
+    // This doesn't work because closing the struct clones the refcount as well
     //refcount: usize
     _marker: PhantomData<RcInner<T>>,
 }
 
+// A Weak does not keep the value alive, only the allocation. So it cannot be
+// dereferenced, it has to be upgraded to an Rc first (which can fail if all
+// the Rc's are gone)
+// This is what breaks cycles: a child points to its parent with a Weak,
+// so the parent is dropped as soon as nothing else holds it
+pub struct Weak<T: ?Sized> {
+    inner: NonNull<RcInner<T>>,
+}
+
 impl<T> Rc<T> {
     pub fn new(v: T) -> Self {
         // Box gives us a pointer on the heap
         let inner = Box::new(RcInner {
+            strong: Cell::new(1),
+            // the implicit weak reference held by all the Rc's
+            weak: Cell::new(1),
             value: v,
-            refcount: Cell::new(1)
         });
         Rc {
             // Box::into_raw returns the raw pointer
@@ -31,53 +59,983 @@ impl<T> Rc<T> {
             // of scope and box will be deallocated
 
             // SAFETY: Box does not give us a null pointer
-            inner: unsafe { NonNull::new_unchecked(Box::into_raw(inner)) }, 
+            inner: unsafe { NonNull::new_unchecked(Box::into_raw(inner)) },
             _marker: PhantomData,
         }
     }
+
+    // For values that need to point at themselves, eg. a node that hands
+    // out Weak's to itself. f gets a Weak to the allocation the value will
+    // live in, which can be cloned and stored but not upgraded until
+    // new_cyclic returns (strong is 0 until then)
+    pub fn new_cyclic<F>(f: F) -> Rc<T>
+    where
+        F: FnOnce(&Weak<T>) -> T,
+    {
+        let uninit = Box::new(RcInner {
+            strong: Cell::new(0),
+            // this is the Weak passed to f
+            weak: Cell::new(1),
+            value: MaybeUninit::<T>::uninit(),
+        });
+        // RcInner is repr(C) and MaybeUninit<T> has the same layout as T
+        let inner = NonNull::from(Box::leak(uninit)).cast::<RcInner<T>>();
+        let weak = Weak { inner };
+
+        // If f panics, weak is dropped while unwinding. strong is 0 so it
+        // doesn't touch the value, which was never written, and if f didn't
+        // keep any clones around, it frees the allocation
+        let value = f(&weak);
+
+        // SAFETY: the value isn't initialised yet, so no one can have a
+        // reference to it, and we're still the only ones who could upgrade
+        unsafe { ptr::write(ptr::addr_of_mut!((*inner.as_ptr()).value), value) };
+        let rc = Rc {
+            inner,
+            _marker: PhantomData,
+        };
+        rc.inner().strong.set(1);
+        // weak's count becomes the implicit weak held by the Rc's
+        std::mem::forget(weak);
+        rc
+    }
+
+    // Allocate room for a T now and write it later. Use get_mut to get at
+    // the MaybeUninit<T> and assume_init once it has been written
+    pub fn new_uninit() -> Rc<MaybeUninit<T>> {
+        Rc::new(MaybeUninit::uninit())
+    }
+
+    pub fn new_uninit_slice(len: usize) -> Rc<[MaybeUninit<T>]> {
+        // MaybeUninit doesn't need initialising, so the Vec conversion would
+        // just be an extra allocation and copy
+        unsafe {
+            let inner = Rc::<[MaybeUninit<T>]>::allocate_for_layout(
+                Layout::array::<T>(len).unwrap(),
+                |mem| ptr::slice_from_raw_parts_mut(mem.cast::<MaybeUninit<T>>(), len) as *mut _,
+            );
+            Rc::from_inner(inner)
+        }
+    }
+
+    // Take the value back out, if this is the only Rc. Weak's don't stop
+    // this, they just won't be able to upgrade anymore
+    pub fn try_unwrap(this: Self) -> Result<T, Self> {
+        if Rc::strong_count(&this) != 1 {
+            return Err(this);
+        }
+        let this = ManuallyDrop::new(this);
+        let inner = this.inner();
+        inner.strong.set(0);
+        // SAFETY: we were the only Rc, and strong is now 0 so no Weak can
+        // upgrade and observe the value after we move it out
+        let value = unsafe { ptr::read(&inner.value) };
+        // Give up the implicit weak reference, this frees the allocation if
+        // there are no Weak's (without dropping value since strong is 0)
+        drop(Weak { inner: this.inner });
+        Ok(value)
+    }
+
+    // Like try_unwrap, but drops this if it isn't the last Rc. So if every Rc
+    // calls into_inner instead of being dropped, exactly one gets the value
+    pub fn into_inner(this: Self) -> Option<T> {
+        Rc::try_unwrap(this).ok()
+    }
+}
+
+impl<T> Rc<MaybeUninit<T>> {
+    /// # Safety
+    ///
+    /// The value must have been initialised, same as `MaybeUninit::assume_init`.
+    pub unsafe fn assume_init(self) -> Rc<T> {
+        // same layout, so only the type of the pointer changes. The counts
+        // carry over as is, including any Weak's
+        let this = ManuallyDrop::new(self);
+        Rc::from_inner(this.inner.as_ptr().cast())
+    }
+}
+
+impl<T> Rc<[MaybeUninit<T>]> {
+    /// # Safety
+    ///
+    /// Every element must have been initialised.
+    pub unsafe fn assume_init(self) -> Rc<[T]> {
+        let this = ManuallyDrop::new(self);
+        Rc::from_inner(this.inner.as_ptr() as *mut RcInner<[T]>)
+    }
+}
+
+impl<T: Clone> Rc<T> {
+    // Clone on write, like Cow::to_mut. If someone else can see the value,
+    // clone it into a new allocation first so we don't mutate it under them
+    pub fn make_mut(this: &mut Self) -> &mut T {
+        if Rc::strong_count(this) != 1 {
+            // Other Rc's are sharing the value, so give them the old
+            // allocation and take a copy
+            *this = Rc::new((**this).clone());
+        } else if Rc::weak_count(this) != 0 {
+            // We are the only Rc, but there are Weak's that could upgrade.
+            // No need to clone, move the value to a new allocation and leave
+            // the Weak's with a dead one
+            let inner = this.inner();
+            inner.strong.set(0);
+            // SAFETY: strong is 0, so no one can get at the value anymore
+            let value = unsafe { ptr::read(&inner.value) };
+            let old = std::mem::replace(this, Rc::new(value));
+            let old = ManuallyDrop::new(old);
+            // the old allocation now only has the Weak's pointing at it
+            drop(Weak { inner: old.inner });
+        }
+        // SAFETY: we just made sure we are the only Rc and there are no Weak's
+        unsafe { &mut (*this.inner.as_ptr()).value }
+    }
+}
+
+impl<T: ?Sized> Rc<T> {
+    // Associated functions instead of methods, so they don't shadow
+    // methods on T through Deref (same as std)
+    pub fn downgrade(this: &Self) -> Weak<T> {
+        let inner = this.inner();
+        let w = inner.weak.get();
+        inner.weak.set(w + 1);
+        Weak { inner: this.inner }
+    }
+
+    pub fn strong_count(this: &Self) -> usize {
+        this.inner().strong.get()
+    }
+
+    pub fn weak_count(this: &Self) -> usize {
+        // don't count the implicit weak reference
+        this.inner().weak.get() - 1
+    }
+
+    // Only hand out &mut T if no other Rc or Weak can observe the value
+    pub fn get_mut(this: &mut Self) -> Option<&mut T> {
+        if Rc::strong_count(this) == 1 && Rc::weak_count(this) == 0 {
+            // SAFETY: we are the only pointer to the allocation, and we have
+            // &mut self so no one is using the value through this Rc either
+            Some(unsafe { &mut (*this.inner.as_ptr()).value })
+        } else {
+            None
+        }
+    }
+
+    // Whether both point to the same allocation, rather than comparing values
+    pub fn ptr_eq(this: &Self, other: &Self) -> bool {
+        // addr_eq ignores the metadata, vtables for the same type are not
+        // guaranteed to be unique
+        ptr::addr_eq(this.inner.as_ptr(), other.inner.as_ptr())
+    }
+
+    // Gives up ownership without touching the counts, the pointer has to be
+    // passed back to from_raw eventually or the Rc is leaked
+    pub fn into_raw(this: Self) -> *const T {
+        let this = ManuallyDrop::new(this);
+        // SAFETY: addr_of! never creates a reference, and the allocation is alive
+        unsafe { ptr::addr_of!((*this.inner.as_ptr()).value) }
+    }
+
+    /// # Safety
+    ///
+    /// `ptr` must have come from `Rc::into_raw` (possibly coerced to a
+    /// `*const U` where `U` is an unsized version of `T`), and may only be
+    /// turned back into an Rc once.
+    pub unsafe fn from_raw(ptr: *const T) -> Self {
+        // value sits at a fixed offset from the start of the RcInner, which
+        // only depends on the alignment of the value (see repr(C) above)
+        let offset = data_offset(std::mem::align_of_val(&*ptr));
+        // byte_sub keeps the metadata (length or vtable) of the fat pointer
+        let inner = ptr.byte_sub(offset) as *mut RcInner<T>;
+        Self::from_inner(inner)
+    }
+
+    unsafe fn from_inner(inner: *mut RcInner<T>) -> Self {
+        Rc {
+            inner: NonNull::new_unchecked(inner),
+            _marker: PhantomData,
+        }
+    }
+
+    // Allocates an RcInner with room for a value with layout `value_layout` and
+    // sets the counts to 1. The value itself is left uninitialised.
+    // mem_to_inner turns the raw memory into a (possibly fat) pointer, since
+    // only the caller knows the length or vtable to attach to it
+    unsafe fn allocate_for_layout(
+        value_layout: Layout,
+        mem_to_inner: impl FnOnce(*mut u8) -> *mut RcInner<T>,
+    ) -> *mut RcInner<T> {
+        let layout = inner_layout(value_layout);
+        let mem = alloc(layout);
+        if mem.is_null() {
+            handle_alloc_error(layout);
+        }
+        let inner = mem_to_inner(mem);
+        ptr::write(ptr::addr_of_mut!((*inner).strong), Cell::new(1));
+        ptr::write(ptr::addr_of_mut!((*inner).weak), Cell::new(1));
+        inner
+    }
+
+    fn inner(&self) -> &RcInner<T> {
+        // SAFETY: the allocation is alive as long as there is an Rc
+        unsafe { self.inner.as_ref() }
+    }
+}
+
+// Layout of the whole RcInner given the layout of value. RcInner<()> is just
+// the two counts, extend() adds the padding needed to align value after them
+fn inner_layout(value_layout: Layout) -> Layout {
+    Layout::new::<RcInner<()>>()
+        .extend(value_layout)
+        .unwrap()
+        .0
+        .pad_to_align()
+}
+
+fn data_offset(value_align: usize) -> usize {
+    let header = Layout::new::<RcInner<()>>();
+    // round the size of the counts up to the alignment of value
+    (header.size() + value_align - 1) & !(value_align - 1)
+}
+
+// Gives data the metadata (length or vtable) of ptr. ptr.with_addr() keeps the
+// metadata, but also ptr's provenance, and ptr points into another allocation
+// (the Box), so going through the result would be UB, which Miri catches. The
+// stable API has no way to take the provenance from one pointer and the
+// metadata from another (with_metadata_of is unstable), so the address half is
+// written in place, the way std did before ptr::metadata existed. That relies
+// on the address being the first word of a fat pointer, checked against
+// with_addr
+unsafe fn set_data_ptr<T: ?Sized>(mut ptr: *mut T, data: *mut u8) -> *mut T {
+    let expected = ptr.with_addr(data.addr());
+    ptr::write(&mut ptr as *mut *mut T as *mut *mut u8, data);
+    assert!(ptr::eq(ptr, expected), "fat pointer address isn't the first word");
+    ptr
 }
 
 // T doesnt have to implement Clone
 // because we are not copying the inner value
-impl<T> Clone for Rc<T> {
+impl<T: ?Sized> Clone for Rc<T> {
     fn clone(&self) -> Self {
-        let inner = unsafe { self.inner.as_ref() };
-        let c = inner.refcount.get();
-        inner.refcount.set(c + 1); 
-        Rc { 
+        let inner = self.inner();
+        let c = inner.strong.get();
+        inner.strong.set(c + 1);
+        Rc {
             inner: self.inner,
             _marker: PhantomData,
         }
     }
 }
 
-impl<T> std::ops::Deref for Rc<T> {
+impl<T: ?Sized> std::ops::Deref for Rc<T> {
     type Target = T;
     fn deref(&self) -> &Self::Target {
-        // SAFETY self.inner is a Box that is only deallocated when the last Rc 
+        // SAFETY self.inner is a Box that is only deallocated when the last Rc
         // goes away. We have an Rc therefore the Box has not been deallocated,
         // so deref is fine
-        &unsafe { &*self.inner.as_ref() }.value
+        &unsafe { self.inner.as_ref() }.value
     }
 }
 
-impl<T> Drop for Rc<T> {
+impl<T: ?Sized> Drop for Rc<T> {
     fn drop(&mut self) {
-        let inner = unsafe { self.inner.as_ref() };
-        let c = inner.refcount.get();
-        if *c == 1 {
+        let inner = self.inner();
+        let c = inner.strong.get();
+        inner.strong.set(c - 1);
+        if c == 1 {
             //SAFETY: we are the only Rc left and we are being dropped
-            // therefore after us, there will be no Rc's and no references to T
-            drop(inner);
-            let _ = unsafe {
-                Box::from_raw(self.inner.as_ptr())
-            };
+            // therefore after us, there will be no Rc's and no references to T.
+            // Weak's can't give out references to T without upgrading, which
+            // fails now that strong is 0
+            unsafe {
+                std::ptr::drop_in_place(&mut (*self.inner.as_ptr()).value);
+            }
+            // Give up the implicit weak reference. If there are no Weak's left
+            // this frees the allocation, otherwise the last Weak will
+            drop(Weak { inner: self.inner });
+        }
+        // otherwise there are other Rcs so don't drop the value!
+    }
+}
+
+// The rest of the usual traits all look through the Rc at the value,
+// same as std. Two Rc's are equal if their values are, use ptr_eq to
+// check if they are the same allocation
+impl<T: ?Sized + fmt::Debug> fmt::Debug for Rc<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<T: ?Sized + fmt::Display> fmt::Display for Rc<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&**self, f)
+    }
+}
+
+// {:p} prints where the value lives, not the value
+impl<T: ?Sized> fmt::Pointer for Rc<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Pointer::fmt(&(&**self as *const T), f)
+    }
+}
+
+impl<T: ?Sized + PartialEq> PartialEq for Rc<T> {
+    fn eq(&self, other: &Self) -> bool {
+        **self == **other
+    }
+}
+
+impl<T: ?Sized + Eq> Eq for Rc<T> {}
+
+impl<T: ?Sized + PartialOrd> PartialOrd for Rc<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        (**self).partial_cmp(&**other)
+    }
+}
+
+impl<T: ?Sized + Ord> Ord for Rc<T> {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        (**self).cmp(&**other)
+    }
+}
+
+// Has to hash the same as T for Borrow<T> to work in a HashMap
+impl<T: ?Sized + Hash> Hash for Rc<T> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        (**self).hash(state)
+    }
+}
+
+// Lets a HashMap<Rc<str>, V> be looked up with a &str
+impl<T: ?Sized> Borrow<T> for Rc<T> {
+    fn borrow(&self) -> &T {
+        self
+    }
+}
+
+impl<T: ?Sized> AsRef<T> for Rc<T> {
+    fn as_ref(&self) -> &T {
+        self
+    }
+}
+
+impl<T: Default> Default for Rc<T> {
+    fn default() -> Self {
+        Rc::new(T::default())
+    }
+}
+
+impl<T> From<T> for Rc<T> {
+    fn from(t: T) -> Self {
+        Rc::new(t)
+    }
+}
+
+impl<T: ?Sized> fmt::Debug for Weak<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // can't show the value, it may be gone
+        f.write_str("(Weak)")
+    }
+}
+
+// Moves the elements into a new allocation that also holds the counts,
+// so an Rc<[T]> is a single allocation rather than an Rc pointing to a Vec
+impl<T> From<Vec<T>> for Rc<[T]> {
+    fn from(mut v: Vec<T>) -> Self {
+        let len = v.len();
+        unsafe {
+            let inner = Rc::<[T]>::allocate_for_layout(Layout::array::<T>(len).unwrap(), |mem| {
+                ptr::slice_from_raw_parts_mut(mem.cast::<T>(), len) as *mut RcInner<[T]>
+            });
+            ptr::copy_nonoverlapping(
+                v.as_ptr(),
+                ptr::addr_of_mut!((*inner).value).cast::<T>(),
+                len,
+            );
+            // the elements have been moved out, so the Vec must only free its buffer
+            v.set_len(0);
+            Rc::from_inner(inner)
+        }
+    }
+}
+
+impl<T: Clone> From<&[T]> for Rc<[T]> {
+    fn from(v: &[T]) -> Self {
+        Rc::from(v.to_vec())
+    }
+}
+
+// Copies the bytes straight into the new allocation, going through Rc<[u8]>
+// would copy them into a Vec first
+impl From<&str> for Rc<str> {
+    fn from(s: &str) -> Self {
+        let len = s.len();
+        unsafe {
+            // str has the same layout as [u8], so its metadata is the length too
+            let inner = Rc::<str>::allocate_for_layout(Layout::for_value(s), |mem| {
+                ptr::slice_from_raw_parts_mut(mem, len) as *mut RcInner<str>
+            });
+            // valid utf-8, since they came from a str
+            ptr::copy_nonoverlapping(
+                s.as_ptr(),
+                ptr::addr_of_mut!((*inner).value).cast::<u8>(),
+                len,
+            );
+            Rc::from_inner(inner)
+        }
+    }
+}
+
+impl From<String> for Rc<str> {
+    fn from(s: String) -> Self {
+        Rc::from(&s[..])
+    }
+}
+
+// Works for any T, including dyn Trait. The value is moved (bytewise) out of
+// the Box into the Rc allocation, and the Box memory is freed without dropping it
+impl<T: ?Sized> From<Box<T>> for Rc<T> {
+    fn from(b: Box<T>) -> Self {
+        let value_layout = Layout::for_value(&*b);
+        let bptr = Box::into_raw(b);
+        unsafe {
+            // the RcInner gets the same metadata (length or vtable) as the Box
+            let inner = Rc::allocate_for_layout(value_layout, |mem| {
+                set_data_ptr(bptr as *mut RcInner<T>, mem)
+            });
+            ptr::copy_nonoverlapping(
+                bptr as *const u8,
+                ptr::addr_of_mut!((*inner).value) as *mut u8,
+                value_layout.size(),
+            );
+            // Box doesn't allocate for zero sized values
+            if value_layout.size() != 0 {
+                dealloc(bptr as *mut u8, value_layout);
+            }
+            Rc::from_inner(inner)
+        }
+    }
+}
+
+// Unsized coercions, e.g. Rc<[u8; 4]> -> Rc<[u8]> or Rc<closure> -> Rc<dyn Fn()>
+// are only available for user types through the unstable CoerceUnsized trait
+#[cfg(feature = "unsize")]
+impl<T, U> std::ops::CoerceUnsized<Rc<U>> for Rc<T>
+where
+    T: ?Sized + std::marker::Unsize<U>,
+    U: ?Sized,
+{
+}
+
+#[cfg(feature = "unsize")]
+impl<T, U> std::ops::CoerceUnsized<Weak<U>> for Weak<T>
+where
+    T: ?Sized + std::marker::Unsize<U>,
+    U: ?Sized,
+{
+}
+
+// On stable, raw pointers can do the coercion for us, so go through into_raw
+// and from_raw. The `let` only compiles if the pointer type coerces to *const $t,
+// so this can't be used to cast to an unrelated type
+//
+// let f: Rc<dyn Fn() -> i32> = coerce_rc!(Rc::new(|| 42) => dyn Fn() -> i32);
+#[macro_export]
+macro_rules! coerce_rc {
+    ($rc:expr => $t:ty) => {{
+        let raw = $crate::rc::Rc::into_raw($rc);
+        let raw: *const $t = raw;
+        // SAFETY: raw came from into_raw and is only an unsized version of it
+        unsafe { $crate::rc::Rc::from_raw(raw) }
+    }};
+}
+
+impl<T: ?Sized> Weak<T> {
+    // Get an Rc back, unless the value has already been dropped
+    pub fn upgrade(&self) -> Option<Rc<T>> {
+        let inner = self.inner();
+        let c = inner.strong.get();
+        if c == 0 {
+            None
+        } else {
+            inner.strong.set(c + 1);
+            Some(Rc {
+                inner: self.inner,
+                _marker: PhantomData,
+            })
+        }
+    }
+
+    pub fn strong_count(&self) -> usize {
+        self.inner().strong.get()
+    }
+
+    // Like std, 0 once the value is gone, even though this Weak (and maybe
+    // others) still hold the allocation
+    pub fn weak_count(&self) -> usize {
+        let inner = self.inner();
+        if inner.strong.get() == 0 {
+            0
         } else {
-            // there are other Rcs so don't drop the Box!
-            inner.refcount.set(c -1);
+            // don't count the implicit weak reference
+            inner.weak.get() - 1
+        }
+    }
+
+    fn inner(&self) -> &RcInner<T> {
+        // SAFETY: the allocation is only freed once weak hits 0, and
+        // we are holding one
+        unsafe { self.inner.as_ref() }
+    }
+}
+
+impl<T: ?Sized> Clone for Weak<T> {
+    fn clone(&self) -> Self {
+        let inner = self.inner();
+        let w = inner.weak.get();
+        inner.weak.set(w + 1);
+        Weak { inner: self.inner }
+    }
+}
+
+impl<T: ?Sized> Drop for Weak<T> {
+    fn drop(&mut self) {
+        let inner = self.inner();
+        let w = inner.weak.get();
+        inner.weak.set(w - 1);
+        if w == 1 {
+            // SAFETY: there are no Rc's (they hold the implicit weak) and no
+            // other Weak's, so nothing else points at the allocation. The value
+            // has already been dropped by the last Rc, so we must not use
+            // Box::from_raw here as it would drop the value a second time.
+            // Layout::for_value only needs the size/align from the metadata,
+            // which is still valid even though the value has been dropped
+            unsafe {
+                let layout = Layout::for_value(self.inner.as_ref());
+                dealloc(self.inner.as_ptr().cast(), layout);
+            }
         }
     }
 }
 
 // Check video at 1:30+ for PhantomData and DropCheck
-// !Sized means not Sized
\ No newline at end of file
+
+#[cfg(test)]
+mod test {
+    use super::{Rc, Weak};
+    use std::cell::{Cell, RefCell};
+
+    struct Node<'a> {
+        parent: RefCell<Option<Weak<Node<'a>>>>,
+        children: RefCell<Vec<Rc<Node<'a>>>>,
+        drops: &'a Cell<usize>,
+    }
+
+    impl<'a> Node<'a> {
+        fn new(drops: &'a Cell<usize>) -> Rc<Self> {
+            Rc::new(Node {
+                parent: RefCell::new(None),
+                children: RefCell::new(Vec::new()),
+                drops,
+            })
+        }
+
+        fn add_child(parent: &Rc<Self>, child: Rc<Self>) {
+            *child.parent.borrow_mut() = Some(Rc::downgrade(parent));
+            parent.children.borrow_mut().push(child);
+        }
+    }
+
+    impl Drop for Node<'_> {
+        fn drop(&mut self) {
+            self.drops.set(self.drops.get() + 1);
+        }
+    }
+
+    #[test]
+    fn counts() {
+        let a = Rc::new(5);
+        assert_eq!(Rc::strong_count(&a), 1);
+        assert_eq!(Rc::weak_count(&a), 0);
+
+        let w = Rc::downgrade(&a);
+        let b = a.clone();
+        assert_eq!(Rc::strong_count(&a), 2);
+        assert_eq!(Rc::weak_count(&a), 1);
+        assert_eq!(w.strong_count(), 2);
+        assert_eq!(w.weak_count(), 1);
+
+        let w2 = w.clone();
+        assert_eq!(Rc::weak_count(&b), 2);
+        drop(w2);
+        assert_eq!(Rc::weak_count(&b), 1);
+    }
+
+    #[test]
+    fn upgrade() {
+        let a = Rc::new(String::from("hello"));
+        let w = Rc::downgrade(&a);
+        assert_eq!(*w.upgrade().unwrap(), "hello");
+        assert_eq!(Rc::strong_count(&a), 1);
+
+        drop(a);
+        assert!(w.upgrade().is_none());
+        assert_eq!(w.strong_count(), 0);
+        assert_eq!(w.weak_count(), 0);
+    }
+
+    #[test]
+    fn value_dropped_before_allocation() {
+        let drops = Cell::new(0);
+        let a = Node::new(&drops);
+        let w = Rc::downgrade(&a);
+        drop(a);
+        // value is gone even though the Weak is still holding the allocation
+        assert_eq!(drops.get(), 1);
+        assert!(w.upgrade().is_none());
+        drop(w);
+        assert_eq!(drops.get(), 1);
+    }
+
+    #[test]
+    fn tree_drops_cleanly() {
+        let drops = Cell::new(0);
+        let root = Node::new(&drops);
+        let leaf = Node::new(&drops);
+        let mid = Node::new(&drops);
+        Node::add_child(&mid, leaf.clone());
+        Node::add_child(&root, mid.clone());
+
+        let parent = leaf.parent.borrow().as_ref().unwrap().upgrade().unwrap();
+        assert!(std::ptr::eq(&*parent, &*mid));
+        drop(parent);
+
+        // the children point back with Weak's, so they don't keep the parents alive
+        assert_eq!(Rc::strong_count(&root), 1);
+        assert_eq!(Rc::weak_count(&root), 1);
+
+        drop(mid);
+        drop(root);
+        assert_eq!(drops.get(), 2);
+        // the leaf outlived its parent, so it can no longer reach it
+        assert!(leaf.parent.borrow().as_ref().unwrap().upgrade().is_none());
+
+        drop(leaf);
+        assert_eq!(drops.get(), 3);
+    }
+
+    #[test]
+    fn str_and_slices() {
+        let s: Rc<str> = Rc::from("interned");
+        let s2 = s.clone();
+        assert_eq!(&*s2, "interned");
+        assert_eq!(Rc::strong_count(&s), 2);
+
+        let empty: Rc<str> = Rc::from(String::new());
+        assert_eq!(&*empty, "");
+
+        let bytes: Rc<[u8]> = Rc::from(vec![1, 2, 3]);
+        assert_eq!(&*bytes, &[1, 2, 3]);
+        let w = Rc::downgrade(&bytes);
+        drop(bytes);
+        assert!(w.upgrade().is_none());
+
+        // zero sized elements need no space after the counts
+        let units: Rc<[()]> = Rc::from(vec![(), ()]);
+        assert_eq!(units.len(), 2);
+    }
+
+    #[test]
+    fn slice_elements_dropped_once() {
+        let drops = Cell::new(0);
+        let nodes: Vec<_> = (0..3).map(|_| Node::new(&drops)).collect();
+        let rc: Rc<[Rc<Node>]> = Rc::from(nodes);
+        let rc2 = rc.clone();
+        drop(rc);
+        assert_eq!(drops.get(), 0);
+        drop(rc2);
+        assert_eq!(drops.get(), 3);
+    }
+
+    #[test]
+    fn trait_objects() {
+        let b: Box<dyn Fn(i32) -> i32> = Box::new(|x| x + 1);
+        let f: Rc<dyn Fn(i32) -> i32> = Rc::from(b);
+        let g = f.clone();
+        assert_eq!(g(41), 42);
+
+        let offset = 10u64;
+        let f: Rc<dyn Fn() -> u64> = crate::coerce_rc!(Rc::new(move || offset) => dyn Fn() -> u64);
+        assert_eq!(f(), 10);
+
+        let arr: Rc<[u8]> = crate::coerce_rc!(Rc::new([1u8, 2, 3, 4]) => [u8]);
+        assert_eq!(arr.len(), 4);
+    }
+
+    #[test]
+    fn unsized_drop() {
+        let drops = Cell::new(0);
+        let node = Node::new(&drops);
+        let b: Box<dyn Fn() -> usize + '_> = Box::new(move || Rc::strong_count(&node));
+        let a: Rc<dyn Fn() -> usize + '_> = Rc::from(b);
+        assert_eq!(a(), 1);
+        let w = Rc::downgrade(&a);
+        drop(a);
+        assert_eq!(drops.get(), 1);
+        assert!(w.upgrade().is_none());
+    }
+
+    #[test]
+    fn raw_round_trip() {
+        let a = Rc::new(String::from("raw"));
+        let ptr = Rc::into_raw(a);
+        let a = unsafe { Rc::from_raw(ptr) };
+        assert_eq!(*a, "raw");
+        assert_eq!(Rc::strong_count(&a), 1);
+
+        // over aligned values are placed after padding
+        #[repr(align(64))]
+        struct Aligned(u8);
+        let a: Rc<[Aligned]> = Rc::from(vec![Aligned(1), Aligned(2)]);
+        assert_eq!(&*a as *const [Aligned] as *const u8 as usize % 64, 0);
+        let b = unsafe { Rc::from_raw(Rc::into_raw(a)) };
+        assert_eq!(b[1].0, 2);
+    }
+
+    #[test]
+    fn get_mut() {
+        let mut a = Rc::new(1);
+        *Rc::get_mut(&mut a).unwrap() += 1;
+        assert_eq!(*a, 2);
+
+        let b = a.clone();
+        assert!(Rc::get_mut(&mut a).is_none());
+        drop(b);
+
+        let w = Rc::downgrade(&a);
+        assert!(Rc::get_mut(&mut a).is_none());
+        drop(w);
+        assert!(Rc::get_mut(&mut a).is_some());
+
+        let mut s: Rc<str> = Rc::from("abc");
+        Rc::get_mut(&mut s).unwrap().make_ascii_uppercase();
+        assert_eq!(&*s, "ABC");
+    }
+
+    #[test]
+    fn make_mut() {
+        // unique, mutated in place
+        let mut a = Rc::new(String::from("a"));
+        let before = &*a as *const String;
+        Rc::make_mut(&mut a).push('b');
+        assert_eq!(&*a as *const String, before);
+
+        // shared, cloned so the other Rc doesn't see the change
+        let b = a.clone();
+        Rc::make_mut(&mut a).push('c');
+        assert_eq!(*a, "abc");
+        assert_eq!(*b, "ab");
+        assert!(!Rc::ptr_eq(&a, &b));
+        assert_eq!(Rc::strong_count(&a), 1);
+        assert_eq!(Rc::strong_count(&b), 1);
+
+        // only Weak's, moved out and the Weak's are disassociated
+        let w = Rc::downgrade(&a);
+        Rc::make_mut(&mut a).push('d');
+        assert_eq!(*a, "abcd");
+        assert!(w.upgrade().is_none());
+        assert_eq!(Rc::weak_count(&a), 0);
+    }
+
+    #[test]
+    fn make_mut_drops_once() {
+        let drops = Cell::new(0);
+        #[derive(Clone)]
+        struct D<'a>(&'a Cell<usize>);
+        impl Drop for D<'_> {
+            fn drop(&mut self) {
+                self.0.set(self.0.get() + 1);
+            }
+        }
+
+        let mut a = Rc::new(D(&drops));
+        let w = Rc::downgrade(&a);
+        Rc::make_mut(&mut a);
+        assert_eq!(drops.get(), 0);
+        drop(w);
+        drop(a);
+        assert_eq!(drops.get(), 1);
+    }
+
+    #[test]
+    fn try_unwrap() {
+        let a = Rc::new(String::from("x"));
+        let b = a.clone();
+        let a = Rc::try_unwrap(a).unwrap_err();
+        drop(b);
+        let w = Rc::downgrade(&a);
+        assert_eq!(Rc::try_unwrap(a).ok().unwrap(), "x");
+        assert!(w.upgrade().is_none());
+        assert_eq!(w.weak_count(), 0);
+    }
+
+    #[test]
+    fn into_inner() {
+        let a = Rc::new(vec![1, 2]);
+        let b = a.clone();
+        assert_eq!(Rc::into_inner(a), None);
+        assert_eq!(Rc::into_inner(b), Some(vec![1, 2]));
+    }
+
+    #[test]
+    fn ptr_eq() {
+        let a = Rc::new(5);
+        let b = a.clone();
+        let c = Rc::new(5);
+        assert!(Rc::ptr_eq(&a, &b));
+        assert!(!Rc::ptr_eq(&a, &c));
+    }
+
+    #[test]
+    fn new_cyclic() {
+        struct Gadget {
+            me: Weak<Gadget>,
+            name: &'static str,
+        }
+
+        let g = Rc::new_cyclic(|me| {
+            // can't upgrade yet, the Gadget doesn't exist
+            assert!(me.upgrade().is_none());
+            Gadget {
+                me: me.clone(),
+                name: "gadget",
+            }
+        });
+        let me = g.me.upgrade().unwrap();
+        assert!(Rc::ptr_eq(&g, &me));
+        assert_eq!(me.name, "gadget");
+        assert_eq!(Rc::strong_count(&g), 2);
+        assert_eq!(Rc::weak_count(&g), 1);
+        drop(me);
+
+        let w = Rc::downgrade(&g);
+        drop(g);
+        assert!(w.upgrade().is_none());
+    }
+
+    #[test]
+    fn new_cyclic_panics() {
+        let drops = Cell::new(0);
+        let escaped = RefCell::new(None);
+        let r = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            Rc::new_cyclic(|me: &Weak<Node>| {
+                // keep a Weak around past the panic, and make something that
+                // gets dropped while unwinding
+                *escaped.borrow_mut() = Some(me.clone());
+                let _n = Node::new(&drops);
+                panic!("boom");
+            })
+        }));
+        assert!(r.is_err());
+        assert_eq!(drops.get(), 1);
+        let w = escaped.borrow_mut().take().unwrap();
+        assert!(w.upgrade().is_none());
+        assert_eq!(w.strong_count(), 0);
+        // dropping the last Weak frees the allocation without dropping the
+        // value, which never existed
+        drop(w);
+        assert_eq!(drops.get(), 1);
+
+        // without any Weak's escaping, the allocation is freed by the unwind
+        let r = std::panic::catch_unwind(|| Rc::<String>::new_cyclic(|_| panic!("boom")));
+        assert!(r.is_err());
+    }
+
+    #[test]
+    fn new_uninit() {
+        let mut slot = Rc::<String>::new_uninit();
+        Rc::get_mut(&mut slot).unwrap().write(String::from("filled"));
+        let s = unsafe { slot.assume_init() };
+        assert_eq!(*s, "filled");
+
+        let mut slots = Rc::<u32>::new_uninit_slice(3);
+        for (i, slot) in Rc::get_mut(&mut slots).unwrap().iter_mut().enumerate() {
+            slot.write(i as u32 * 2);
+        }
+        let slots = unsafe { slots.assume_init() };
+        assert_eq!(&*slots, &[0, 2, 4]);
+
+        let drops = Cell::new(0);
+        let mut slot = Rc::new_uninit();
+        Rc::get_mut(&mut slot).unwrap().write(Node::new(&drops));
+        let node = unsafe { slot.assume_init() };
+        drop(node);
+        assert_eq!(drops.get(), 1);
+    }
+
+    #[test]
+    fn formatting_matches_std() {
+        let ours = Rc::new(vec![1, 2]);
+        let std = std::rc::Rc::new(vec![1, 2]);
+        assert_eq!(format!("{:?}", ours), format!("{:?}", std));
+        assert_eq!(format!("{:#?}", ours), format!("{:#?}", std));
+        let s: Rc<str> = Rc::from("hi");
+        assert_eq!(format!("{:>4}", s), format!("{:>4}", std::rc::Rc::<str>::from("hi")));
+        assert_eq!(format!("{:?}", Rc::downgrade(&s)), "(Weak)");
+        assert_eq!(format!("{:p}", ours), format!("{:p}", &*ours as *const Vec<i32>));
+    }
+
+    #[test]
+    fn comparisons_use_the_value() {
+        let a = Rc::new(1);
+        let b = Rc::new(1);
+        assert_eq!(a, b);
+        assert!(!Rc::ptr_eq(&a, &b));
+        assert!(Rc::new(1) < Rc::new(2));
+        assert_eq!(Rc::new(3).cmp(&Rc::new(2)), std::cmp::Ordering::Greater);
+        assert_eq!(Rc::new(f64::NAN).partial_cmp(&Rc::new(1.0)), None);
+    }
+
+    #[test]
+    fn collection_keys() {
+        use std::collections::{BTreeSet, HashMap};
+
+        let mut m: HashMap<Rc<str>, u32> = HashMap::new();
+        let key: Rc<str> = Rc::from("key");
+        m.insert(key.clone(), 1);
+        // looked up by &str through Borrow<str>
+        assert_eq!(m.get("key"), Some(&1));
+        *m.get_mut(&key).unwrap() += 1;
+        assert_eq!(m["key"], 2);
+
+        let set: BTreeSet<Rc<i32>> = [3, 1, 2].into_iter().map(Rc::from).collect();
+        assert_eq!(set.iter().map(|x| **x).collect::<Vec<_>>(), vec![1, 2, 3]);
+        assert!(set.contains(&2));
+
+        use std::hash::BuildHasher;
+        let h = std::collections::hash_map::RandomState::new();
+        assert_eq!(h.hash_one(Rc::new("x")), h.hash_one("x"));
+    }
+
+    #[test]
+    fn default_and_as_ref() {
+        let d: Rc<Vec<u8>> = Rc::default();
+        assert!(d.is_empty());
+        fn len<S: AsRef<[u8]>>(s: S) -> usize {
+            s.as_ref().len()
+        }
+        let bytes: Rc<[u8]> = Rc::from(vec![1, 2, 3]);
+        assert_eq!(len(bytes), 3);
+    }
+
+    #[cfg(feature = "unsize")]
+    #[test]
+    fn coerce_unsized() {
+        let f: Rc<dyn Fn() -> i32> = Rc::new(|| 7);
+        assert_eq!(f(), 7);
+        let w = Rc::downgrade(&Rc::new([0u8; 3]));
+        let w: Weak<[u8]> = w;
+        assert!(w.upgrade().is_none());
+    }
+}
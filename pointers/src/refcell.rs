@@ -59,6 +59,22 @@ mod one {
             }
         }
     }
+
+    #[cfg(test)]
+    mod test {
+        use super::RefCell;
+
+        #[test]
+        #[ignore = "expected UB: two &mut to the same value, see soundness.rs"]
+        fn aliasing_mut() {
+            let c = RefCell::new(0);
+            let a = c.borrow_mut().unwrap();
+            // should be refused, but the state was never set to Exclusive
+            let b = c.borrow_mut().unwrap();
+            *b += 1;
+            *a += 1;
+        }
+    }
 }
 
 
@@ -133,90 +149,373 @@ mod two {
 }
 
 
-mod three {
+pub mod three {
     use super::*;
-    // cell::two is because the Cell implementation is under module two
-    use crate::cell::two::Cell;
+    // Used to be the unsound cell::two::Cell, which hands out &T from get
+    use crate::cell::Cell;
+    use std::fmt;
+    use std::marker::PhantomData;
+    #[cfg(feature = "debug-borrows")]
+    use std::panic::Location;
+    use std::ptr::NonNull;
 
     // Cell doesnt allow to use to get the reference to thing that inside
     // but that's fine, still the enum can be cheaply cloned
     #[derive(Copy, Clone)]
     enum RefState {
         Unshared,
+        // The sites of the shared borrows are kept in Borrows, there can be
+        // any number of them
         Shared(usize),
-        Exclusive,
+        Exclusive(BorrowSite),
     }
 
-    pub struct RefCell<T> {
-        value: UnsafeCell<T>,
+    // Where a borrow was taken, so a conflicting borrow can tell you who is
+    // holding the RefCell. Only recorded with the debug-borrows feature,
+    // otherwise it is zero sized and costs nothing
+    #[derive(Copy, Clone, Debug)]
+    struct BorrowSite {
+        #[cfg(feature = "debug-borrows")]
+        location: &'static Location<'static>,
+    }
+
+    impl BorrowSite {
+        // Every function between the user's call and here has to be
+        // #[track_caller] as well, or we would record a line in this file
+        #[track_caller]
+        fn caller() -> Self {
+            BorrowSite {
+                #[cfg(feature = "debug-borrows")]
+                location: Location::caller(),
+            }
+        }
+
+        fn fmt_suffix(&self, _f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            #[cfg(feature = "debug-borrows")]
+            write!(_f, " at {}", self.location)?;
+            Ok(())
+        }
+    }
+
+    // What the Ref's and RefMut's update as they come and go, split out of
+    // RefCell so they don't have to know about T
+    struct Borrows {
         state: Cell<RefState>,
+        // Where each live shared borrow was taken, oldest first, with an id
+        // so that dropping a Ref takes out its own entry and no other
+        #[cfg(feature = "debug-borrows")]
+        shared: Cell<Vec<(usize, BorrowSite)>>,
+        #[cfg(feature = "debug-borrows")]
+        next_id: Cell<usize>,
+    }
+
+    impl Borrows {
+        fn new() -> Self {
+            Borrows {
+                state: Cell::new(RefState::Unshared),
+                #[cfg(feature = "debug-borrows")]
+                shared: Cell::new(Vec::new()),
+                #[cfg(feature = "debug-borrows")]
+                next_id: Cell::new(0),
+            }
+        }
+
+        // The oldest of the shared borrows that are still alive
+        #[cfg(feature = "debug-borrows")]
+        fn shared_site(&self) -> BorrowSite {
+            let sites = self.shared.take();
+            let site = sites[0].1;
+            self.shared.set(sites);
+            site
+        }
+
+        #[cfg(not(feature = "debug-borrows"))]
+        fn shared_site(&self) -> BorrowSite {
+            BorrowSite {}
+        }
+
+        #[cfg(feature = "debug-borrows")]
+        #[track_caller]
+        fn add_shared_site(&self) -> usize {
+            let id = self.next_id.get();
+            self.next_id.set(id.wrapping_add(1));
+            let mut sites = self.shared.take();
+            sites.push((id, BorrowSite::caller()));
+            self.shared.set(sites);
+            id
+        }
+
+        #[cfg(feature = "debug-borrows")]
+        fn remove_shared_site(&self, id: usize) {
+            let mut sites = self.shared.take();
+            sites.retain(|&(i, _)| i != id);
+            self.shared.set(sites);
+        }
+    }
+
+    // value is last so that T can be unsized, eg. RefCell<[u8]>
+    pub struct RefCell<T: ?Sized> {
+        borrows: Borrows,
+        value: UnsafeCell<T>,
+    }
+
+    // Returned by try_borrow, the value is currently mutably borrowed
+    #[derive(Debug)]
+    pub struct BorrowError {
+        // the outstanding RefMut
+        held_by: BorrowSite,
+    }
+
+    // Returned by try_borrow_mut, the value is currently borrowed
+    #[derive(Debug)]
+    pub struct BorrowMutError {
+        // the outstanding RefMut, or the oldest of the Refs
+        held_by: BorrowSite,
+    }
+
+    #[cfg(feature = "debug-borrows")]
+    impl BorrowError {
+        // Where the RefMut that is in the way was created
+        pub fn location(&self) -> &'static Location<'static> {
+            self.held_by.location
+        }
+    }
+
+    #[cfg(feature = "debug-borrows")]
+    impl BorrowMutError {
+        // Where the RefMut that is in the way was created, or if it is Refs,
+        // the oldest of them that is still alive
+        pub fn location(&self) -> &'static Location<'static> {
+            self.held_by.location
+        }
+    }
+
+    impl fmt::Display for BorrowError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.write_str("already mutably borrowed")?;
+            self.held_by.fmt_suffix(f)
+        }
+    }
+
+    impl fmt::Display for BorrowMutError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.write_str("already borrowed")?;
+            self.held_by.fmt_suffix(f)
+        }
+    }
+
+    impl std::error::Error for BorrowError {}
+    impl std::error::Error for BorrowMutError {}
+
+    // The part of a Ref that keeps track of the shared borrow. It is split out
+    // from Ref so that Ref::map can hand it over to a Ref pointing somewhere else,
+    // the borrow is only given up when this is dropped
+    struct BorrowRef<'b> {
+        borrows: &'b Borrows,
+        // which of the shared sites is ours
+        #[cfg(feature = "debug-borrows")]
+        id: usize,
+    }
+
+    impl<'b> BorrowRef<'b> {
+        #[track_caller]
+        fn new(borrows: &'b Borrows) -> Result<Self, BorrowError> {
+            match borrows.state.get() {
+                RefState::Unshared => borrows.state.set(RefState::Shared(1)),
+                RefState::Shared(n) => borrows.state.set(RefState::Shared(n + 1)),
+                RefState::Exclusive(held_by) => return Err(BorrowError { held_by }),
+            }
+            Ok(BorrowRef {
+                borrows,
+                #[cfg(feature = "debug-borrows")]
+                id: borrows.add_shared_site(),
+            })
+        }
     }
 
-    // Just reference to refcell
-    pub struct Ref<'refcell, T> {
-        refcell: &'refcell RefCell<T>,
+    impl Clone for BorrowRef<'_> {
+        // the clone is a borrow of its own, taken wherever Ref::clone was called
+        #[track_caller]
+        fn clone(&self) -> Self {
+            match self.borrows.state.get() {
+                RefState::Exclusive(_) | RefState::Unshared => unreachable!(),
+                RefState::Shared(n) => self.borrows.state.set(RefState::Shared(n + 1)),
+            }
+            BorrowRef {
+                borrows: self.borrows,
+                #[cfg(feature = "debug-borrows")]
+                id: self.borrows.add_shared_site(),
+            }
+        }
     }
 
-    impl<T> Drop for Ref<'_, T> {
+    impl Drop for BorrowRef<'_> {
         fn drop(&mut self) {
-            match self.refcell.state.get() {
-                RefState::Exclusive | RefState::Unshared => unreachable!(),
-                RefState::Shared(1) => {
-                    self.refcell.state.set(RefState::Unshared);
-                }
-                RefState::Shared(n) => {
-                    self.refcell.state.set(RefState::Shared(n - 1));
+            match self.borrows.state.get() {
+                RefState::Exclusive(_) | RefState::Unshared => unreachable!(),
+                RefState::Shared(1) => self.borrows.state.set(RefState::Unshared),
+                RefState::Shared(n) => self.borrows.state.set(RefState::Shared(n - 1)),
+            }
+            #[cfg(feature = "debug-borrows")]
+            self.borrows.remove_shared_site(self.id);
+        }
+    }
+
+    struct BorrowRefMut<'b> {
+        borrows: &'b Borrows,
+    }
+
+    impl<'b> BorrowRefMut<'b> {
+        #[track_caller]
+        fn new(borrows: &'b Borrows) -> Result<Self, BorrowMutError> {
+            match borrows.state.get() {
+                RefState::Unshared => {
+                    borrows.state.set(RefState::Exclusive(BorrowSite::caller()));
+                    Ok(BorrowRefMut { borrows })
                 }
+                RefState::Shared(_) => Err(BorrowMutError {
+                    held_by: borrows.shared_site(),
+                }),
+                RefState::Exclusive(held_by) => Err(BorrowMutError { held_by }),
             }
         }
     }
+
+    impl Drop for BorrowRefMut<'_> {
+        fn drop(&mut self) {
+            match self.borrows.state.get() {
+                RefState::Shared(_) | RefState::Unshared => unreachable!(),
+                RefState::Exclusive(_) => self.borrows.state.set(RefState::Unshared),
+            }
+        }
+    }
+
+    // No longer just a reference to the refcell, since after Ref::map
+    // it points into the value rather than at all of it
+    pub struct Ref<'b, T: ?Sized> {
+        value: NonNull<T>,
+        borrow: BorrowRef<'b>,
+    }
+
     // Deref is the trait that gets invoked evertime you used the dot operator
     // If you do T.some_method() and if T doesn't have that method, but derefs to something 
     // that does, then deref trait gets called
     // A way to automatically following deeper into a type
-    impl<T> std::ops::Deref for Ref <'_, T> {
+    impl<T: ?Sized> std::ops::Deref for Ref<'_, T> {
         type Target = T;
         fn deref(&self) -> &Self::Target {
             // SAFETY
             // A Ref is only created if no exclusive references have been given out
             // Once it is given out, state is set to Shared so no exclusive references
             // are given out. So deferencing into a shared reference is fine
-            unsafe { &*self.refcell.value.get() }
+            unsafe { self.value.as_ref() }
         }
     }
 
-    pub struct RefMut<'refcell, T> {
-        refcell: &'refcell RefCell<T>,
-    }
+    // These are associated functions rather than methods, so that they don't
+    // get in the way of methods with the same name on T
+    impl<'b, T: ?Sized> Ref<'b, T> {
+        // Another shared borrow of the same value, without going through the RefCell
+        #[allow(clippy::should_implement_trait)]
+        #[track_caller]
+        pub fn clone(orig: &Ref<'b, T>) -> Ref<'b, T> {
+            Ref {
+                value: orig.value,
+                borrow: orig.borrow.clone(),
+            }
+        }
 
-    impl<T> Drop for RefMut<'_, T> {
-        fn drop(&mut self) {
-            match self.refcell.state.get() {
-                RefState::Shared(_) | RefState::Unshared => unreachable!(),
-                RefState::Exclusive => {
-                    self.refcell.state.set(RefState::Unshared);
-                }
+        // Narrow the borrow down to a part of the value, eg. a struct field.
+        // The RefCell stays borrowed until the new Ref is dropped
+        pub fn map<U: ?Sized, F>(orig: Ref<'b, T>, f: F) -> Ref<'b, U>
+        where
+            F: FnOnce(&T) -> &U,
+        {
+            Ref {
+                value: NonNull::from(f(&*orig)),
+                borrow: orig.borrow,
             }
         }
+
+        // Same as map, but the part might not be there. Hands the original
+        // back if it isn't
+        pub fn filter_map<U: ?Sized, F>(orig: Ref<'b, T>, f: F) -> Result<Ref<'b, U>, Self>
+        where
+            F: FnOnce(&T) -> Option<&U>,
+        {
+            match f(&*orig) {
+                Some(value) => Ok(Ref {
+                    value: NonNull::from(value),
+                    borrow: orig.borrow,
+                }),
+                None => Err(orig),
+            }
+        }
+    }
+
+    pub struct RefMut<'b, T: ?Sized> {
+        value: NonNull<T>,
+        borrow: BorrowRefMut<'b>,
+        // NonNull is covariant, but we hand out &mut T so RefMut must be
+        // invariant over T like &mut T is
+        _marker: PhantomData<&'b mut T>,
     }
 
-    impl<T> std::ops::Deref for RefMut<'_, T> {
+    impl<T: ?Sized> std::ops::Deref for RefMut<'_, T> {
         type Target = T;
         fn deref(&self) -> &Self::Target {
             // SAFETY
             // See safety for DerefMut
-            unsafe { &*self.refcell.value.get() }
+            unsafe { self.value.as_ref() }
         }
     }
 
-    impl<T> std::ops::DerefMut for RefMut<'_, T> {
+    impl<T: ?Sized> std::ops::DerefMut for RefMut<'_, T> {
         fn deref_mut(&mut self) -> &mut Self::Target {
             // SAFETY
             // A RefMut is only created if no other references have been given out
             // Once it is given out, state is set to Exclusive so no future references
             // are given out. So we have an exclusive lease on the innter value
             // so mutably deferencing is fine
-            unsafe { &mut *self.refcell.value.get() }
+            unsafe { self.value.as_mut() }
+        }
+    }
+
+    // Ref and RefMut format as whatever they point to, like std
+    impl<T: ?Sized + fmt::Debug> fmt::Debug for Ref<'_, T> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            fmt::Debug::fmt(&**self, f)
+        }
+    }
+
+    impl<T: ?Sized + fmt::Display> fmt::Display for Ref<'_, T> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            fmt::Display::fmt(&**self, f)
+        }
+    }
+
+    impl<T: ?Sized + fmt::Debug> fmt::Debug for RefMut<'_, T> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            fmt::Debug::fmt(&**self, f)
+        }
+    }
+
+    impl<T: ?Sized + fmt::Display> fmt::Display for RefMut<'_, T> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            fmt::Display::fmt(&**self, f)
+        }
+    }
+
+    impl<'b, T: ?Sized> RefMut<'b, T> {
+        pub fn map<U: ?Sized, F>(mut orig: RefMut<'b, T>, f: F) -> RefMut<'b, U>
+        where
+            F: FnOnce(&mut T) -> &mut U,
+        {
+            RefMut {
+                value: NonNull::from(f(&mut *orig)),
+                borrow: orig.borrow,
+                _marker: PhantomData,
+            }
         }
     }
 
@@ -229,36 +528,284 @@ mod three {
     impl<T> RefCell<T> {
         pub fn new(value: T) -> Self {
             Self {
+                borrows: Borrows::new(),
                 value: UnsafeCell::new(value),
-                state: Cell::new(RefState::Unshared),
             }
         }
 
-        // If you have a shared reference, there are no exclusive references
-        pub fn borrow(&self) -> Option<Ref<'_, T>> {
-            match self.state.get() {
-                RefState::Unshared => {
-                    self.state.set(RefState::Shared(1));
-                    Some(Ref{refcell: self})
-                }
-                RefState::Shared(n) => {
-                    self.state.set(RefState::Shared(n + 1));
-                    Some(Ref{refcell: self})
-                }
-                RefState::Exclusive => None
+        // We own the RefCell, so there can't be any Ref's or RefMut's around
+        pub fn into_inner(self) -> T {
+            self.value.into_inner()
+        }
+
+        // Panics if the value is currently borrowed
+        #[track_caller]
+        pub fn replace(&self, t: T) -> T {
+            std::mem::replace(&mut *self.borrow_mut(), t)
+        }
+
+        #[track_caller]
+        pub fn replace_with<F>(&self, f: F) -> T
+        where
+            F: FnOnce(&mut T) -> T,
+        {
+            let mut_borrow = &mut *self.borrow_mut();
+            let replacement = f(mut_borrow);
+            std::mem::replace(mut_borrow, replacement)
+        }
+
+        // Panics if either value is currently borrowed
+        #[track_caller]
+        pub fn swap(&self, other: &RefCell<T>) {
+            // swapping with itself is a no-op, and borrowing it mutably twice
+            // would panic
+            if std::ptr::eq(self, other) {
+                return;
             }
+            std::mem::swap(&mut *self.borrow_mut(), &mut *other.borrow_mut())
+        }
+
+        #[track_caller]
+        pub fn take(&self) -> T
+        where
+            T: Default,
+        {
+            self.replace(T::default())
+        }
+    }
+
+    impl<T: ?Sized> RefCell<T> {
+        // If you have a shared reference, there are no exclusive references
+        #[track_caller]
+        pub fn try_borrow(&self) -> Result<Ref<'_, T>, BorrowError> {
+            let borrow = BorrowRef::new(&self.borrows)?;
+            Ok(Ref {
+                // SAFETY: UnsafeCell::get never returns null
+                value: unsafe { NonNull::new_unchecked(self.value.get()) },
+                borrow,
+            })
         }
 
         // If you have an exclusive reference, there are no shared references
-        pub fn borrow_mut(&self) -> Option<RefMut<'_, T>> {
-            if let RefState::Unshared = self.state.get() {
-                // SAFETY: no other references have been given out since state
-                // would be Shared or Exclusive
-                self.state.set(RefState::Exclusive);
-                Some(RefMut { refcell: self })
-            } else {
-                None
+        #[track_caller]
+        pub fn try_borrow_mut(&self) -> Result<RefMut<'_, T>, BorrowMutError> {
+            let borrow = BorrowRefMut::new(&self.borrows)?;
+            Ok(RefMut {
+                // SAFETY: UnsafeCell::get never returns null
+                value: unsafe { NonNull::new_unchecked(self.value.get()) },
+                borrow,
+                _marker: PhantomData,
+            })
+        }
+
+        // #[track_caller] makes the panic point at whoever called borrow,
+        // rather than at this line
+        #[track_caller]
+        pub fn borrow(&self) -> Ref<'_, T> {
+            match self.try_borrow() {
+                Ok(r) => r,
+                Err(e) => panic!("{}", e),
+            }
+        }
+
+        #[track_caller]
+        pub fn borrow_mut(&self) -> RefMut<'_, T> {
+            match self.try_borrow_mut() {
+                Ok(r) => r,
+                Err(e) => panic!("{}", e),
+            }
+        }
+
+        // &mut self already proves there are no borrows, so no need to check
+        pub fn get_mut(&mut self) -> &mut T {
+            self.value.get_mut()
+        }
+    }
+
+    #[cfg(test)]
+    mod test {
+        use super::{Ref, RefCell, RefMut};
+
+        struct Point {
+            x: i32,
+            y: Option<String>,
+        }
+
+        #[test]
+        fn borrow_rules() {
+            let c = RefCell::new(5);
+            let r1 = c.borrow();
+            let r2 = c.try_borrow().unwrap();
+            assert_eq!(*r1 + *r2, 10);
+            assert!(c.try_borrow_mut().is_err());
+            drop(r1);
+            assert!(c.try_borrow_mut().is_err());
+            drop(r2);
+
+            let mut m = c.borrow_mut();
+            *m += 1;
+            assert!(c.try_borrow().is_err());
+            assert!(c.try_borrow_mut().is_err());
+            drop(m);
+            assert_eq!(*c.borrow(), 6);
+        }
+
+        #[test]
+        #[should_panic(expected = "already mutably borrowed")]
+        fn borrow_panics() {
+            let c = RefCell::new(0);
+            let _m = c.borrow_mut();
+            let _r = c.borrow();
+        }
+
+        #[test]
+        #[should_panic(expected = "already borrowed")]
+        fn borrow_mut_panics() {
+            let c = RefCell::new(0);
+            let _r = c.borrow();
+            let _m = c.borrow_mut();
+        }
+
+        #[test]
+        fn error_messages() {
+            let c = RefCell::new(0);
+            let r = c.borrow();
+            assert!(c.try_borrow_mut().err().unwrap().to_string().starts_with("already borrowed"));
+            drop(r);
+            let _m = c.borrow_mut();
+            assert!(c
+                .try_borrow()
+                .err()
+                .unwrap()
+                .to_string()
+                .starts_with("already mutably borrowed"));
+        }
+
+        #[test]
+        fn replace_swap_take() {
+            let a = RefCell::new(vec![1]);
+            let b = RefCell::new(vec![2, 3]);
+            assert_eq!(a.replace(vec![4]), vec![1]);
+            assert_eq!(a.replace_with(|v| v.iter().map(|x| x * 2).collect()), vec![4]);
+            assert_eq!(*a.borrow(), vec![8]);
+
+            a.swap(&b);
+            a.swap(&a);
+            assert_eq!(*a.borrow(), vec![2, 3]);
+            assert_eq!(b.take(), vec![8]);
+            assert!(b.borrow().is_empty());
+            assert_eq!(a.into_inner(), vec![2, 3]);
+        }
+
+        #[test]
+        fn get_mut() {
+            let mut c = RefCell::new(1);
+            *c.get_mut() += 1;
+            assert_eq!(*c.borrow(), 2);
+        }
+
+        #[test]
+        fn ref_projections() {
+            let c = RefCell::new(Point {
+                x: 1,
+                y: Some(String::from("y")),
+            });
+
+            let x = Ref::map(c.borrow(), |p| &p.x);
+            let x2 = Ref::clone(&x);
+            assert_eq!(*x2, 1);
+            drop(x);
+            // the projection still holds the borrow
+            assert!(c.try_borrow_mut().is_err());
+            drop(x2);
+
+            let y = Ref::filter_map(c.borrow(), |p| p.y.as_deref()).ok().unwrap();
+            assert_eq!(&*y, "y");
+            drop(y);
+
+            {
+                let mut p = c.borrow_mut();
+                p.y = None;
             }
+            let p = Ref::filter_map(c.borrow(), |p| p.y.as_deref()).err().unwrap();
+            assert_eq!(p.x, 1);
+            drop(p);
+
+            let mut x = RefMut::map(c.borrow_mut(), |p| &mut p.x);
+            *x += 41;
+            assert!(c.try_borrow().is_err());
+            drop(x);
+            assert_eq!(c.borrow().x, 42);
+        }
+
+        #[cfg(feature = "debug-borrows")]
+        #[test]
+        fn conflicting_borrow_location() {
+            let c = RefCell::new(0);
+            let line = line!() + 1;
+            let m = c.borrow_mut();
+            let e = c.try_borrow().err().unwrap();
+            assert_eq!(e.location().file(), file!());
+            assert_eq!(e.location().line(), line);
+            assert_eq!(
+                e.to_string(),
+                format!("already mutably borrowed at {}", e.location())
+            );
+            let e = c.try_borrow_mut().err().unwrap();
+            assert_eq!(e.to_string(), format!("already borrowed at {}", e.location()));
+            drop(m);
+
+            // shared borrows report the oldest one, even through a projection
+            let line = line!() + 1;
+            let r = Ref::map(c.borrow(), |x| x);
+            let _r2 = c.borrow();
+            let e = c.try_borrow_mut().err().unwrap();
+            assert_eq!(e.location().line(), line);
+            assert!(e.to_string().starts_with("already borrowed at "));
+            // only live borrows are reported, so now it is _r2
+            drop(r);
+            let e = c.try_borrow_mut().err().unwrap();
+            assert_eq!(e.location().line(), line + 1);
+            // a clone is a borrow of its own
+            let line = line!() + 1;
+            let r3 = Ref::clone(&_r2);
+            drop(_r2);
+            let e = c.try_borrow_mut().err().unwrap();
+            assert_eq!(e.location().line(), line);
+            drop(r3);
+            assert!(c.try_borrow_mut().is_ok());
+        }
+
+        #[cfg(feature = "debug-borrows")]
+        #[test]
+        #[should_panic(expected = "already borrowed at src/refcell.rs:")]
+        fn conflicting_borrow_panics_with_location() {
+            let c = RefCell::new(0);
+            let _r = c.borrow();
+            c.replace(1);
+        }
+
+        #[test]
+        fn formatting_matches_std() {
+            let ours = RefCell::new(vec![1]);
+            let std = std::cell::RefCell::new(vec![1]);
+            assert_eq!(format!("{:?}", ours.borrow()), format!("{:?}", std.borrow()));
+            assert_eq!(format!("{:?}", ours.borrow_mut()), format!("{:?}", std.borrow_mut()));
+
+            let ours = RefCell::new(1.5);
+            let std = std::cell::RefCell::new(1.5);
+            assert_eq!(format!("{:.3}", ours.borrow()), format!("{:.3}", std.borrow()));
+            assert_eq!(format!("{:>5}", ours.borrow_mut()), format!("{:>5}", std.borrow_mut()));
+        }
+
+        #[test]
+        fn unsized_value() {
+            let c: &RefCell<[i32]> = &RefCell::new([1, 2, 3]);
+            c.borrow_mut()[0] = 10;
+            assert_eq!(c.borrow().iter().sum::<i32>(), 15);
         }
     }
-}
\ No newline at end of file
+}
+
+// Module three is the working version
+pub use three::{BorrowError, BorrowMutError, Ref, RefCell, RefMut};
@@ -0,0 +1,341 @@
+// Tests aimed at the unsafe code in this crate rather than at its behaviour.
+// On their own they mostly just pass. They are meant to be run under Miri,
+// which checks every access against the aliasing model and reports UB even
+// when the program happens to do the right thing:
+//
+//   cargo +nightly miri test
+//   MIRIFLAGS="-Zmiri-tree-borrows" cargo +nightly miri test
+//
+// Stacked borrows is the default and the stricter of the two. Everything
+// outside the expected UB list below should pass under both.
+//
+// Arc is checked with loom instead, which runs a test under every possible
+// interleaving of its threads (Miri only sees the one the OS picked):
+//
+//   cargo test --release --features loom soundness::loom
+//
+// EXPECTED UB
+//
+// These are the deliberately broken versions kept around to show why the
+// later ones are written the way they are. Their tests are #[ignore]d so the
+// normal test run stays green, run them with --ignored to watch them fail:
+//
+//   cargo +nightly miri test -- --ignored
+//
+// - cell::two::test::bad
+//     cell::two::Cell is Sync, so two threads call set at the same time.
+//     Miri: "Data race detected". Without Miri the count just comes out wrong.
+// - cell::two::test::bad2
+//     cell::two::Cell::get returns &T, and set writes through the cell while
+//     that &T is alive. The &T points at the String inside the Cell, which
+//     is never freed, only the old heap buffer is, so there is no
+//     use-after-free. Miri: aliasing violation, the write through
+//     UnsafeCell::get invalidates the &T (stacked borrows pops its tag, tree
+//     borrows disables it). Without Miri it prints "world", not "hello".
+// - refcell::one::test::aliasing_mut
+//     refcell::one can't record borrows through &self, so borrow_mut hands
+//     out two &mut T to the same value.
+//     Miri: stacked borrows reports the first &mut being used after the second
+//     one invalidated it.
+//
+// The regression tests further down pin the fixed versions (cell::three,
+// refcell::three, rc, once) doing the same kinds of things.
+
+mod cell {
+    use crate::cell::Cell;
+
+    #[test]
+    fn set_while_copy_is_held() {
+        let c = Cell::new(1);
+        let copy = c.get();
+        c.set(2);
+        assert_eq!(copy, 1);
+        assert_eq!(c.get(), 2);
+    }
+
+    #[test]
+    fn replace_drops_nothing_in_place() {
+        // replace moves the old value out before anyone can observe the cell
+        let c = Cell::new(vec![1]);
+        let old = c.replace(vec![2]);
+        c.set(vec![3]);
+        assert_eq!(old, vec![1]);
+        assert_eq!(c.take(), vec![3]);
+    }
+
+    #[test]
+    fn swap_with_itself() {
+        let c = Cell::new(String::from("same"));
+        c.swap(&c);
+        assert_eq!(c.take(), "same");
+    }
+
+    #[test]
+    fn slice_of_cells_aliasing() {
+        let mut v = vec![1, 2, 3];
+        {
+            let cells = Cell::from_mut(&mut v[..]).as_slice_of_cells();
+            let (a, b) = (&cells[0], &cells[0]);
+            a.set(b.get() + 10);
+            cells[1].swap(&cells[2]);
+            for c in cells {
+                c.update(|x| x * 2);
+            }
+        }
+        // the &mut is usable again once the cells are gone
+        v.push(4);
+        assert_eq!(v, [22, 6, 4, 4]);
+    }
+}
+
+mod refcell {
+    use crate::refcell::{Ref, RefCell, RefMut};
+
+    #[test]
+    fn shared_borrows_coexist() {
+        let c = RefCell::new(vec![1, 2, 3]);
+        let a = c.borrow();
+        let b = Ref::map(c.borrow(), |v| &v[1]);
+        let a2 = Ref::clone(&a);
+        drop(a);
+        assert_eq!(a2[0] + *b, 3);
+    }
+
+    #[test]
+    fn mut_borrow_after_shared_released() {
+        let c = RefCell::new(String::from("a"));
+        {
+            let r = c.borrow();
+            assert_eq!(&*r, "a");
+        }
+        let mut m = c.borrow_mut();
+        // grows and possibly reallocates the String. Any &str taken through
+        // the shared borrow above would now be dangling
+        m.push_str("bcdefghijklmnopqrstuvwxyz");
+        drop(m);
+        assert_eq!(c.borrow().len(), 26);
+    }
+
+    #[test]
+    fn mapped_ref_mut_reborrows() {
+        let c = RefCell::new((1, String::new()));
+        let mut s = RefMut::map(c.borrow_mut(), |t| &mut t.1);
+        let r: &mut String = &mut s;
+        r.push('x');
+        s.push('y');
+        drop(s);
+        assert_eq!(c.borrow().1, "xy");
+    }
+
+    #[test]
+    fn replace_with_sees_current_value() {
+        let c = RefCell::new(vec![1]);
+        let old = c.replace_with(|v| {
+            v.push(2);
+            vec![3]
+        });
+        assert_eq!(old, vec![1, 2]);
+        assert_eq!(c.into_inner(), vec![3]);
+    }
+}
+
+mod rc {
+    use crate::rc::{Rc, Weak};
+    use std::cell::Cell;
+
+    #[test]
+    fn reference_outlives_other_clones() {
+        let a = Rc::new(String::from("value"));
+        let r: &str = &a;
+        let b = a.clone();
+        drop(b);
+        assert_eq!(r, "value");
+    }
+
+    #[test]
+    fn weak_keeps_allocation_not_value() {
+        let a = Rc::new(vec![1]);
+        let w = Rc::downgrade(&a);
+        let w2 = w.clone();
+        drop(a);
+        assert!(w.upgrade().is_none());
+        drop(w);
+        assert_eq!(w2.weak_count(), 0);
+        assert!(w2.upgrade().is_none());
+    }
+
+    #[test]
+    fn unsized_round_trips() {
+        let s: Rc<str> = Rc::from("str");
+        let s = unsafe { Rc::from_raw(Rc::into_raw(s)) };
+        let f: Rc<dyn Fn() -> usize> = Rc::from(Box::new(|| 3usize) as Box<dyn Fn() -> usize>);
+        let f = unsafe { Rc::from_raw(Rc::into_raw(f)) };
+        let w = Rc::downgrade(&f);
+        assert_eq!(f() + s.len(), 6);
+        drop(f);
+        assert!(w.upgrade().is_none());
+
+        // zero sized values, in a Box that never allocated
+        let z: Rc<dyn Fn() -> u8> = Rc::from(Box::new(|| 0u8) as Box<dyn Fn() -> u8>);
+        assert_eq!(z(), 0);
+        let empty: Rc<[String]> = Rc::from(Vec::new());
+        assert!(empty.is_empty());
+    }
+
+    #[test]
+    fn move_out_with_weak_outstanding() {
+        let a = Rc::new(String::from("a"));
+        let w = Rc::downgrade(&a);
+        let s = Rc::try_unwrap(a).ok().unwrap();
+        assert!(w.upgrade().is_none());
+        drop(w);
+        assert_eq!(s, "a");
+
+        let mut a = Rc::new(String::from("b"));
+        let w = Rc::downgrade(&a);
+        Rc::make_mut(&mut a).push('c');
+        drop(w);
+        assert_eq!(*a, "bc");
+    }
+
+    #[test]
+    fn get_mut_reference_then_clone() {
+        let mut a = Rc::new(1);
+        let m = Rc::get_mut(&mut a).unwrap();
+        *m = 2;
+        let b = a.clone();
+        assert_eq!(*b, 2);
+    }
+
+    #[test]
+    fn cyclic_and_uninit() {
+        struct Me(Weak<Me>, u8);
+        let me = Rc::new_cyclic(|w| Me(w.clone(), 7));
+        assert_eq!(me.0.upgrade().unwrap().1, 7);
+
+        // panicking with a clone of the Weak escaping
+        let escaped = Cell::new(None);
+        let r = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            Rc::<String>::new_cyclic(|w| {
+                escaped.set(Some(w.clone()));
+                panic!()
+            })
+        }));
+        assert!(r.is_err());
+        assert!(escaped.take().unwrap().upgrade().is_none());
+
+        let mut slots = Rc::<String>::new_uninit_slice(2);
+        for slot in Rc::get_mut(&mut slots).unwrap() {
+            slot.write(String::from("s"));
+        }
+        let slots = unsafe { slots.assume_init() };
+        assert_eq!(slots.concat(), "ss");
+    }
+}
+
+mod once {
+    use crate::once::OnceCell;
+
+    #[test]
+    fn reference_survives_failed_set() {
+        let c = OnceCell::new();
+        let r = c.get_or_init(|| String::from("first"));
+        assert!(c.set(String::from("second")).is_err());
+        assert_eq!(r, "first");
+    }
+
+    #[test]
+    fn reentrant_init_does_not_overwrite() {
+        let c = OnceCell::new();
+        let r = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            c.get_or_init(|| {
+                c.get_or_init(|| vec![1]);
+                vec![2]
+            });
+        }));
+        assert!(r.is_err());
+        assert!(c.get().is_none());
+    }
+}
+
+#[cfg(feature = "loom")]
+mod loom {
+    use crate::arc::Arc;
+    use loom::sync::atomic::{AtomicUsize, Ordering};
+    use loom::thread;
+
+    struct D(std::sync::Arc<AtomicUsize>);
+
+    impl Drop for D {
+        fn drop(&mut self) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn drop_exactly_once() {
+        loom::model(|| {
+            let drops = std::sync::Arc::new(AtomicUsize::new(0));
+            let a = Arc::new(D(drops.clone()));
+            let b = a.clone();
+            let t = thread::spawn(move || drop(b));
+            drop(a);
+            t.join().unwrap();
+            assert_eq!(drops.load(Ordering::Relaxed), 1);
+        });
+    }
+
+    #[test]
+    fn upgrade_races_last_drop() {
+        loom::model(|| {
+            let drops = std::sync::Arc::new(AtomicUsize::new(0));
+            let a = Arc::new(D(drops.clone()));
+            let w = Arc::downgrade(&a);
+            let t = thread::spawn(move || {
+                // either gets the value, or sees it is already gone
+                if let Some(a) = w.upgrade() {
+                    assert_eq!(drops_of(&a), 0);
+                }
+            });
+            drop(a);
+            t.join().unwrap();
+            assert_eq!(drops.load(Ordering::Relaxed), 1);
+        });
+    }
+
+    #[test]
+    fn get_mut_races_downgrade() {
+        loom::model(|| {
+            let mut a = Arc::new(0);
+            let b = a.clone();
+            let t = thread::spawn(move || {
+                let w = Arc::downgrade(&b);
+                drop(b);
+                w.upgrade().map(|a| *a)
+            });
+            if let Some(v) = Arc::get_mut(&mut a) {
+                *v = 1;
+            }
+            let seen = t.join().unwrap();
+            // get_mut only succeeds once the other thread is done with both
+            // its Arc and its Weak, so it can never see the write
+            assert_ne!(seen, Some(1));
+        });
+    }
+
+    #[test]
+    fn into_inner_exactly_one() {
+        loom::model(|| {
+            let a = Arc::new(5);
+            let b = a.clone();
+            let t = thread::spawn(move || Arc::into_inner(b));
+            let mine = Arc::into_inner(a);
+            let theirs = t.join().unwrap();
+            assert_eq!(mine.xor(theirs), Some(5));
+        });
+    }
+
+    fn drops_of(d: &D) -> usize {
+        d.0.load(Ordering::Relaxed)
+    }
+}